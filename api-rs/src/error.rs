@@ -0,0 +1,92 @@
+use std::io;
+
+use daemon::error::{ConfigError, DbError, JobError};
+use rocket::{
+    Request, error,
+    http::{Status, StatusClass},
+    response::{self, Responder, Response, content::RawJson},
+};
+use serde_json::json;
+use thiserror::Error;
+
+/// Everything a request can fail with, answered with a status and a json body
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error(transparent)]
+    Db(#[from] DbError),
+
+    #[error(transparent)]
+    Job(#[from] JobError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("couldn't serialize the response: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("couldn't render the page: {0}")]
+    Template(#[from] handlebars::RenderError),
+
+    #[error("{0}")]
+    BadRequest(String),
+
+    /// the body is the json of the kaleidoscope the request collides with
+    #[error("{0}")]
+    Conflict(String),
+}
+
+fn db_status(e: &DbError) -> Status {
+    if e.is_not_found() {
+        Status::NotFound
+    } else if e.is_invalid_input() {
+        Status::BadRequest
+    } else if e.is_unavailable() {
+        Status::ServiceUnavailable
+    } else {
+        Status::InternalServerError
+    }
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::Db(e) | ApiError::Job(JobError::Db(e)) => db_status(e),
+            ApiError::Job(JobError::NoDistinctParameters(_)) => Status::Conflict,
+            ApiError::Io(e) if e.kind() == io::ErrorKind::NotFound => Status::NotFound,
+            ApiError::BadRequest(_) => Status::BadRequest,
+            ApiError::Conflict(_) => Status::Conflict,
+            _ => Status::InternalServerError,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        if status.class() == StatusClass::ServerError {
+            error!("{} {} failed: {}", req.method(), req.uri(), self);
+        }
+
+        let body = match self {
+            ApiError::Conflict(body) => body,
+            e => json!({ "error": e.to_string() }).to_string(),
+        };
+        Response::build_from(RawJson(body).respond_to(req)?).status(status).ok()
+    }
+}
+
+/// Why the API couldn't start or stopped
+#[derive(Debug, Error)]
+pub enum StartupError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error(transparent)]
+    Db(#[from] DbError),
+
+    #[error("couldn't load the page templates: {0}")]
+    Template(#[from] Box<handlebars::TemplateError>),
+
+    #[error(transparent)]
+    Rocket(#[from] Box<rocket::Error>),
+}
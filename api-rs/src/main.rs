@@ -1,87 +1,459 @@
-use std::sync::Arc;
+use std::{
+    env::var,
+    path::{Path, PathBuf},
+};
 
-use daemon::database::{
-    all_kaleidoscopes, init_database, insert_new_parameterized_job, single_kaleidoscopes,
+use daemon::{
+    DEFAULT_CONFIG_PATH,
+    config::DaemonConfig,
+    database::{
+        JobStatus, all_batches, all_kaleidoscopes, batch_kaleidoscopes, batch_progress, cancel_batch,
+        cancel_kaleidoscope, connect_database, create_batch, get_specific_job_parameters,
+        insert_new_parameterized_job, job_progress, kaleidoscope_frames_state, set_rating,
+        set_starred, similar_kaleidoscopes, single_kaleidoscopes, status_history, stored_assets,
+    },
+    duplicates::{DuplicateConfig, distinct_random, find_duplicate},
 };
+use clap::ValueEnum;
 use handlebars::Handlebars;
-use rocket::{State, get, launch, put, response::content::RawHtml, routes, serde::json::Json, tokio::sync::Mutex};
+use rocket::{
+    Either, State, debug, delete, fs::NamedFile, get, post, http::ContentType, put,
+    response::{Redirect, content::RawHtml},
+    routes, serde::json::Json,
+    tokio::fs::read,
+};
+use serde::Deserialize;
 use serde_json::{Map, json};
 use sqlx::{Pool, Postgres};
-use tarascope::shader::KaleidoArgs;
+use tarascope::{
+    Tarascope,
+    encoder::archive::{ArchiveArgs, ArchiveFormat, create_archive},
+    generator::GeneratorSpec,
+    joblog::{job_log_path, job_logs},
+    shader::KaleidoArgs,
+};
+
+use crate::error::{ApiError, StartupError};
+
+mod error;
 
 struct ApiState<'a> {
-    pool: Arc<Mutex<Pool<Postgres>>>,
-    handlebars: Handlebars<'a>
+    pool: Pool<Postgres>,
+    handlebars: Handlebars<'a>,
+    /// directory the daemon renders the kaleidoscopes into
+    output_dir: String,
+    duplicates: DuplicateConfig,
 }
 
+/// Distance up to which `/<id>/similar` lists kaleidoscopes if no epsilon is given
+static SIMILAR_EPSILON: f32 = 0.15;
+
 #[get("/")]
-async fn full(state: &State<ApiState<'_>>) -> String {
-    let lock = state.pool.lock().await;
+async fn full(state: &State<ApiState<'_>>) -> Result<String, ApiError> {
     //let p = lock.acquire().await;
-    let res = all_kaleidoscopes(&lock).await.unwrap();
+    let res = all_kaleidoscopes(&state.pool).await?;
 
-    serde_json::to_string(&res).unwrap()
+    Ok(serde_json::to_string(&res)?)
 }
 
-#[put("/", data = "<data>")]
-async fn new(state: &State<ApiState<'_>>, data: Json<KaleidoArgs>) -> String {
-    println!("{:?}", data);
+/// Queues a kaleidoscope with the given parameters. Parameters that nearly match an
+/// existing kaleidoscope are rejected with the closest one, unless `force` is set.
+#[put("/?<force>", data = "<data>")]
+async fn new(
+    state: &State<ApiState<'_>>,
+    data: Json<KaleidoArgs>,
+    force: Option<bool>,
+) -> Result<String, ApiError> {
 
-    let lock = state.pool.lock().await;
+    if !force.unwrap_or(false)
+        && let Some(similar) = find_duplicate(&state.pool, &data.0, &state.duplicates).await?
+    {
+        return Err(ApiError::Conflict(serde_json::to_string(&similar)?));
+    }
 
-    insert_new_parameterized_job(&lock, data.0).await.unwrap();
-    String::from("ok")
+    debug!("queueing kaleidoscope {:?}", data.0);
+    insert_new_parameterized_job(&state.pool, data.0).await?;
+    Ok(String::from("ok"))
 }
 
 #[put("/random")]
-async fn random(state: &State<ApiState<'_>>) -> String {
-    let data = KaleidoArgs::random();
-    println!("{:?}", data);
+async fn random(state: &State<ApiState<'_>>) -> Result<String, ApiError> {
 
-    let lock = state.pool.lock().await;
+    let data = distinct_random(&state.pool, &state.duplicates).await?;
+    debug!("queueing random kaleidoscope {:?}", data);
 
-    insert_new_parameterized_job(&lock, data).await.unwrap();
-    String::from("ok")
+    insert_new_parameterized_job(&state.pool, data).await?;
+    Ok(String::from("ok"))
 }
 
 #[get("/<id>")]
-async fn single(state: &State<ApiState<'_>>, id: &str) -> String {
-    let lock = state.pool.lock().await;
+async fn single(state: &State<ApiState<'_>>, id: &str) -> Result<String, ApiError> {
     //let p = lock.acquire().await;
-    let res = single_kaleidoscopes(&lock, &String::from(id))
-        .await
-        .unwrap();
+    let res = single_kaleidoscopes(&state.pool, &String::from(id)).await?;
+
+    Ok(serde_json::to_string(&res)?)
+}
+
+#[get("/<id>/progress")]
+async fn progress(state: &State<ApiState<'_>>, id: &str) -> Result<Option<String>, ApiError> {
+    let Some(res) = job_progress(&state.pool, &String::from(id)).await? else {
+        return Ok(None);
+    };
+
+    Ok(Some(serde_json::to_string(&res)?))
+}
+
+#[get("/<id>/history")]
+async fn history(state: &State<ApiState<'_>>, id: &str) -> Result<String, ApiError> {
+    let res = status_history(&state.pool, &String::from(id)).await?;
+
+    Ok(serde_json::to_string(&res)?)
+}
+
+/// Keys and urls of the assets of a kaleidoscope in the storage
+#[get("/<id>/assets")]
+async fn assets(state: &State<ApiState<'_>>, id: &str) -> Result<String, ApiError> {
+    let res = stored_assets(&state.pool, &String::from(id)).await?;
+
+    Ok(serde_json::to_string(&res)?)
+}
+
+/// Blender and ffmpeg logs of a job, including the rotated copies
+#[get("/<id>/logs")]
+async fn logs(state: &State<ApiState<'_>>, id: &str) -> Result<Option<String>, ApiError> {
+    if !valid_id(id) {
+        return Ok(None);
+    }
+    let dirs = Tarascope::new(state.output_dir.clone()).paths_for_job(&String::from(id));
+    Ok(Some(serde_json::to_string(&job_logs(&dirs))?))
+}
+
+/// Content of a single log, `rotated` picks an older copy and `tail` only returns the last lines
+#[get("/<id>/logs/<name>?<rotated>&<tail>")]
+async fn log(
+    state: &State<ApiState<'_>>,
+    id: &str,
+    name: &str,
+    rotated: Option<usize>,
+    tail: Option<usize>,
+) -> Option<String> {
+    if !valid_id(id) {
+        return None;
+    }
+    let dirs = Tarascope::new(state.output_dir.clone()).paths_for_job(&String::from(id));
+    let path = job_log_path(&dirs, name, rotated.unwrap_or(0))?;
+    let content = read(path).await.ok()?;
+    let content = String::from_utf8_lossy(&content);
+
+    Some(match tail {
+        Some(lines) => {
+            let tail: Vec<&str> = content.lines().rev().take(lines).collect();
+            tail.into_iter().rev().collect::<Vec<_>>().join("\n")
+        }
+        None => content.into_owned(),
+    })
+}
+
+/// Kaleidoscopes with parameters close to the given one, closest first
+#[get("/<id>/similar?<epsilon>&<limit>")]
+async fn similar(
+    state: &State<ApiState<'_>>,
+    id: &str,
+    epsilon: Option<f32>,
+    limit: Option<i64>,
+) -> Result<String, ApiError> {
+    let kargs = get_specific_job_parameters(&state.pool, &String::from(id)).await?;
+    let res = similar_kaleidoscopes(
+        &state.pool,
+        &kargs,
+        epsilon.unwrap_or(SIMILAR_EPSILON),
+        limit.unwrap_or(20),
+    )
+    .await?;
+
+    Ok(serde_json::to_string(&res)?)
+}
+
+/// Cancels a queued or running kaleidoscope. The frames a running job has rendered
+/// so far get deleted unless `keep_frames` is set.
+async fn cancel(
+    state: &State<ApiState<'_>>,
+    id: &str,
+    keep_frames: Option<bool>,
+) -> Result<Option<String>, ApiError> {
+    let cancelled =
+        cancel_kaleidoscope(&state.pool, &String::from(id), keep_frames.unwrap_or(false)).await?;
+
+    // nothing left to cancel
+    Ok(cancelled.then(|| String::from("ok")))
+}
+
+#[delete("/<id>?<keep_frames>")]
+async fn delete_job(
+    state: &State<ApiState<'_>>,
+    id: &str,
+    keep_frames: Option<bool>,
+) -> Result<Option<String>, ApiError> {
+    cancel(state, id, keep_frames).await
+}
+
+#[post("/<id>/cancel?<keep_frames>")]
+async fn cancel_job(
+    state: &State<ApiState<'_>>,
+    id: &str,
+    keep_frames: Option<bool>,
+) -> Result<Option<String>, ApiError> {
+    cancel(state, id, keep_frames).await
+}
+
+/// Stars a kaleidoscope, starred ones are kept by the retention policies
+#[put("/<id>/star")]
+async fn star(state: &State<ApiState<'_>>, id: &str) -> Result<Option<String>, ApiError> {
+    Ok(set_starred(&state.pool, &String::from(id), true).await?.then(|| String::from("ok")))
+}
+
+#[delete("/<id>/star")]
+async fn unstar(state: &State<ApiState<'_>>, id: &str) -> Result<Option<String>, ApiError> {
+    Ok(set_starred(&state.pool, &String::from(id), false).await?.then(|| String::from("ok")))
+}
+
+/// Rates a kaleidoscope from 1 to 5, well rated ones are kept by the retention policies
+#[put("/<id>/rating/<rating>")]
+async fn rate(state: &State<ApiState<'_>>, id: &str, rating: i16) -> Result<Option<String>, ApiError> {
+    if !(1..=5).contains(&rating) {
+        return Err(ApiError::BadRequest(String::from("rating must be between 1 and 5")));
+    }
+    let found = set_rating(&state.pool, &String::from(id), Some(rating)).await?;
+    Ok(found.then(|| String::from("ok")))
+}
+
+#[delete("/<id>/rating")]
+async fn unrate(state: &State<ApiState<'_>>, id: &str) -> Result<Option<String>, ApiError> {
+    Ok(set_rating(&state.pool, &String::from(id), None).await?.then(|| String::from("ok")))
+}
 
-    serde_json::to_string(&res).unwrap()
+#[derive(Debug, Deserialize)]
+struct NewBatch {
+    name: String,
+    /// render stills instead of animations
+    #[serde(default)]
+    still: bool,
+    #[serde(flatten)]
+    spec: GeneratorSpec,
+}
+
+#[get("/batches")]
+async fn batches(state: &State<ApiState<'_>>) -> Result<String, ApiError> {
+    let res = all_batches(&state.pool).await?;
+
+    Ok(serde_json::to_string(&res)?)
+}
+
+/// Queues a batch, e.g. `{"name": "waves", "texture": "wave", "fixed": {"repetition": 6}, "count": 10}`
+/// Near duplicates are left out, the answer tells how many kaleidoscopes were queued
+#[post("/batches", data = "<data>")]
+async fn new_batch(state: &State<ApiState<'_>>, data: Json<NewBatch>) -> Result<String, ApiError> {
+    let batch = create_batch(&state.pool, &data.name, data.still, &data.spec, &state.duplicates).await?;
+
+    Ok(serde_json::to_string(&batch)?)
+}
+
+// batch routes rank below the kaleidoscope routes, `/<id>/progress` would collide otherwise
+#[get("/batches/<id>", rank = 1)]
+async fn batch(state: &State<ApiState<'_>>, id: i64) -> Result<Option<String>, ApiError> {
+    let Some(res) = batch_progress(&state.pool, id).await? else {
+        return Ok(None);
+    };
+
+    Ok(Some(serde_json::to_string(&res)?))
+}
+
+/// Cancels the queued and running kaleidoscopes of a batch
+async fn cancel_all(
+    state: &State<ApiState<'_>>,
+    id: i64,
+    keep_frames: Option<bool>,
+) -> Result<Option<String>, ApiError> {
+    let cancelled = cancel_batch(&state.pool, id, keep_frames.unwrap_or(false)).await?;
+
+    Ok(cancelled.map(|cancelled| json!({ "cancelled": cancelled }).to_string()))
+}
+
+#[delete("/batches/<id>?<keep_frames>", rank = 1)]
+async fn delete_batch(
+    state: &State<ApiState<'_>>,
+    id: i64,
+    keep_frames: Option<bool>,
+) -> Result<Option<String>, ApiError> {
+    cancel_all(state, id, keep_frames).await
+}
+
+#[post("/batches/<id>/cancel?<keep_frames>", rank = 1)]
+async fn cancel_batch_jobs(
+    state: &State<ApiState<'_>>,
+    id: i64,
+    keep_frames: Option<bool>,
+) -> Result<Option<String>, ApiError> {
+    cancel_all(state, id, keep_frames).await
+}
+
+/// Looks up the MIME type of a streaming playlist or segment
+fn stream_content_type(file: &Path) -> Option<ContentType> {
+    match file.extension()?.to_str()? {
+        "m3u8" => ContentType::parse_flexible("application/vnd.apple.mpegurl"),
+        "ts" => ContentType::parse_flexible("video/mp2t"),
+        "mpd" => ContentType::parse_flexible("application/dash+xml"),
+        "m4s" => ContentType::parse_flexible("video/iso.segment"),
+        "mp4" => Some(ContentType::MP4),
+        _ => None,
+    }
+}
+
+/// The id is joined into a filesystem path, don't let it escape the output directory
+fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// A streaming file served from the job folder, or a redirect to its stored copy
+type StreamFile = Either<(ContentType, NamedFile), Redirect>;
+
+/// Serves a playlist or segment from the job folder. Files that only exist in the
+/// storage anymore, e.g. after uploading them with `delete_local`, are redirected to.
+async fn stream_file(
+    state: &State<ApiState<'_>>,
+    id: &str,
+    kind: &str,
+    file: PathBuf,
+) -> Result<Option<StreamFile>, ApiError> {
+    if !valid_id(id) {
+        return Ok(None);
+    }
+    let Some(content_type) = stream_content_type(&file) else {
+        return Ok(None);
+    };
+    let path = PathBuf::from(&state.output_dir).join(id).join(kind).join(&file);
+    if let Ok(local) = NamedFile::open(path).await {
+        return Ok(Some(Either::Left((content_type, local))));
+    }
+
+    let name = format!("{}/{}", kind, file.to_string_lossy());
+    let stored = stored_assets(&state.pool, &String::from(id)).await?;
+    Ok(stored
+        .into_iter()
+        .find(|asset| asset.name == name)
+        .map(|asset| Either::Right(Redirect::temporary(asset.url))))
+}
+
+#[get("/<id>/hls/<file..>")]
+async fn hls(state: &State<ApiState<'_>>, id: &str, file: PathBuf) -> Result<Option<StreamFile>, ApiError> {
+    stream_file(state, id, "hls", file).await
+}
+
+#[get("/<id>/dash/<file..>")]
+async fn dash(state: &State<ApiState<'_>>, id: &str, file: PathBuf) -> Result<Option<StreamFile>, ApiError> {
+    stream_file(state, id, "dash", file).await
+}
+
+/// Downloads the frame sequence of a job, packaging it first if there is no archive yet.
+/// `exr` adds the 16 bit frames of jobs that were rendered with them.
+#[get("/<id>/archive?<format>&<exr>")]
+async fn archive(
+    state: &State<ApiState<'_>>,
+    id: &str,
+    format: Option<&str>,
+    exr: Option<bool>,
+) -> Result<Option<(ContentType, NamedFile)>, ApiError> {
+    if !valid_id(id) {
+        return Ok(None);
+    }
+    let format = match format {
+        Some(f) => ArchiveFormat::from_str(f, true).map_err(ApiError::BadRequest)?,
+        None => ArchiveFormat::Zip,
+    };
+    let args = ArchiveArgs {
+        format,
+        exr: exr.unwrap_or(false),
+    };
+
+    let id = String::from(id);
+    match kaleidoscope_frames_state(&state.pool, &id).await? {
+        None => return Ok(None),
+        // retention deleted the frames, an archive left behind would be incomplete
+        Some((_, true)) => return Ok(None),
+        Some((JobStatus::Done, false)) => {}
+        Some((status, false)) => {
+            let error = format!("the kaleidoscope is {:?}, only finished ones can be archived", status);
+            return Err(ApiError::Conflict(json!({ "error": error.to_lowercase() }).to_string()));
+        }
+    }
+
+    let dirs = Tarascope::new(state.output_dir.clone()).paths_for_job(&id);
+    let mut path = PathBuf::from(dirs.archive_path(&args.extension()));
+    if !path.exists() {
+        path = create_archive(&dirs, &args).await?;
+    }
+
+    let content_type = ContentType::parse_flexible(format.mime_type()).unwrap_or(ContentType::Binary);
+    let file = NamedFile::open(path).await?;
+    Ok(Some((content_type, file)))
 }
 
 #[get("/")]
-async fn frontpage(state: &State<ApiState<'_>>) -> Result<RawHtml<String>, std::io::Error> {
-    let lock = state.pool.lock().await;
-    let data = all_kaleidoscopes(&lock).await.unwrap();
+async fn frontpage(state: &State<ApiState<'_>>) -> Result<RawHtml<String>, ApiError> {
+    let data = all_kaleidoscopes(&state.pool).await?;
 
     let mut content = Map::new();
     content.insert("content".to_string(), json!(data));
-    let res = state.handlebars.render("main", &content).unwrap();
+    let res = state.handlebars.render("main", &content)?;
 
     Ok(RawHtml(res))
 }
 
-#[launch]
-async fn rocket() -> _ {
+#[get("/batches/<id>")]
+async fn batch_gallery(state: &State<ApiState<'_>>, id: i64) -> Result<Option<RawHtml<String>>, ApiError> {
+    let Some(batch) = batch_progress(&state.pool, id).await? else {
+        return Ok(None);
+    };
+    let data = batch_kaleidoscopes(&state.pool, id).await?;
+
+    let mut content = Map::new();
+    content.insert("batch".to_string(), json!(batch));
+    content.insert("content".to_string(), json!(data));
+    let res = state.handlebars.render("batch", &content)?;
+
+    Ok(Some(RawHtml(res)))
+}
+
+#[rocket::main]
+async fn main() -> Result<(), StartupError> {
     let _ = dotenv::dotenv().ok();
 
-    let pool = init_database().await.unwrap();
+    // the api shares the database, the output directory and the duplicate check with the daemon
+    let config = match var("TARASCOPE_CONFIG") {
+        Ok(path) => DaemonConfig::load(Path::new(&path), true),
+        Err(_) => DaemonConfig::load(Path::new(DEFAULT_CONFIG_PATH), false),
+    }?;
+    let pool = connect_database(&config.database).await?;
 
     let mut handlebars = Handlebars::new();
 
-    handlebars.register_template_file("main", "./api-rs/index.hbs").unwrap();
+    handlebars.register_template_file("main", "./api-rs/index.hbs").map_err(Box::new)?;
+    handlebars.register_template_file("batch", "./api-rs/batch.hbs").map_err(Box::new)?;
 
     rocket::build()
         .manage(ApiState {
-            pool: Arc::new(Mutex::new(pool)),
-            handlebars
+            pool,
+            handlebars,
+            output_dir: config.output.directory,
+            duplicates: config.duplicates,
         })
-        .mount("/", routes![frontpage])
-        .mount("/api", routes![full, single, new, random])
+        .mount("/", routes![frontpage, batch_gallery])
+        .mount("/api", routes![full, single, progress, history, assets, similar, hls, dash, archive, new, random, delete_job, cancel_job])
+        .mount("/api", routes![batches, new_batch, batch, delete_batch, cancel_batch_jobs])
+        .mount("/api", routes![star, unstar, rate, unrate])
+        .mount("/api", routes![logs, log])
+        .launch()
+        .await
+        .map_err(Box::new)?;
+    Ok(())
 }
@@ -0,0 +1,22 @@
+-- Schema as it was deployed from the original pg_dump (tarascopes.sql).
+-- Databases that were created from that dump already have these tables.
+
+CREATE TABLE IF NOT EXISTS public.tarascope (
+    id uuid NOT NULL,
+    parameters json NOT NULL,
+    "timestamp" timestamp without time zone DEFAULT CURRENT_TIMESTAMP,
+    status integer DEFAULT 0,
+    CONSTRAINT tarascope_pk PRIMARY KEY (id)
+);
+
+CREATE TABLE IF NOT EXISTS public.frames (
+    kaleidoid uuid NOT NULL,
+    frame_count integer NOT NULL,
+    frameid serial NOT NULL,
+    "timestamp" timestamp without time zone DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT frames_unique UNIQUE (frameid),
+    CONSTRAINT frames_tarascope_fk FOREIGN KEY (kaleidoid) REFERENCES public.tarascope(id) ON DELETE CASCADE
+);
+
+-- placeholder view of the dump, it read parameters -> 'frame' which never existed
+DROP VIEW IF EXISTS public.newview;
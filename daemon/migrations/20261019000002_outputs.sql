@@ -0,0 +1,13 @@
+-- encode progress, generated assets and failure details of a kaleidoscope
+
+ALTER TABLE public.tarascope
+    ADD COLUMN IF NOT EXISTS encode_target text,
+    ADD COLUMN IF NOT EXISTS encode_progress real,
+    ADD COLUMN IF NOT EXISTS thumbnail text,
+    ADD COLUMN IF NOT EXISTS poster text,
+    ADD COLUMN IF NOT EXISTS poster_frame integer,
+    ADD COLUMN IF NOT EXISTS contact_sheet text,
+    ADD COLUMN IF NOT EXISTS error text,
+    ADD COLUMN IF NOT EXISTS stderr_tail text;
+
+CREATE INDEX IF NOT EXISTS frames_kaleidoid_idx ON public.frames USING btree (kaleidoid);
@@ -0,0 +1,45 @@
+-- durable render queue, claimed by the workers with FOR UPDATE SKIP LOCKED
+
+CREATE TABLE public.jobs (
+    id bigserial NOT NULL,
+    kind text NOT NULL,
+    kaleidoid uuid,
+    state text DEFAULT 'pending'::text NOT NULL,
+    priority integer DEFAULT 0 NOT NULL,
+    attempts integer DEFAULT 0 NOT NULL,
+    next_attempt_at timestamp without time zone,
+    last_error text,
+    created_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP,
+    claimed_at timestamp without time zone,
+    finished_at timestamp without time zone,
+    worker text,
+    lease_expires_at timestamp without time zone,
+    CONSTRAINT jobs_pk PRIMARY KEY (id),
+    CONSTRAINT jobs_kaleidoid_unique UNIQUE (kaleidoid),
+    CONSTRAINT jobs_tarascope_fk FOREIGN KEY (kaleidoid) REFERENCES public.tarascope(id) ON DELETE CASCADE
+);
+
+CREATE INDEX jobs_pending_idx ON public.jobs USING btree (priority DESC, id) WHERE (state = 'pending'::text);
+
+CREATE INDEX jobs_lease_idx ON public.jobs USING btree (lease_expires_at) WHERE (state = 'running'::text);
+
+CREATE TABLE public.workers (
+    name text NOT NULL,
+    capabilities text[] DEFAULT '{}'::text[] NOT NULL,
+    started_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP,
+    heartbeat_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT workers_pk PRIMARY KEY (name)
+);
+
+CREATE VIEW public.worker_status AS
+ SELECT w.name,
+    w.capabilities,
+    w.heartbeat_at,
+    (w.heartbeat_at > (CURRENT_TIMESTAMP - '00:02:00'::interval)) AS alive,
+    j.id AS job,
+    j.kind,
+    j.kaleidoid,
+    j.claimed_at,
+    j.lease_expires_at
+   FROM (public.workers w
+     LEFT JOIN public.jobs j ON (((j.worker = w.name) AND (j.state = 'running'::text))));
@@ -0,0 +1,61 @@
+-- replaces the status integers (0 new, 1 waiting, 2 running, 3 done, 4 failed)
+
+CREATE TYPE public.job_status AS ENUM (
+    'queued',
+    'rendering',
+    'encoding',
+    'uploading',
+    'done',
+    'failed',
+    'cancelled'
+);
+
+-- views depending on the status column have to go before its type can change
+DROP VIEW IF EXISTS public.showcase;
+DROP VIEW IF EXISTS public.progress;
+
+ALTER TABLE public.tarascope ALTER COLUMN status DROP DEFAULT;
+ALTER TABLE public.tarascope ALTER COLUMN status TYPE public.job_status USING (
+    CASE status
+        WHEN 2 THEN 'rendering'
+        WHEN 3 THEN 'done'
+        WHEN 4 THEN 'failed'
+        ELSE 'queued'
+    END
+)::public.job_status;
+ALTER TABLE public.tarascope ALTER COLUMN status SET DEFAULT 'queued'::public.job_status;
+ALTER TABLE public.tarascope ALTER COLUMN status SET NOT NULL;
+
+CREATE TABLE public.status_history (
+    historyid bigserial NOT NULL,
+    kaleidoid uuid NOT NULL,
+    status public.job_status NOT NULL,
+    ts timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT status_history_pk PRIMARY KEY (historyid),
+    CONSTRAINT status_history_tarascope_fk FOREIGN KEY (kaleidoid) REFERENCES public.tarascope(id) ON DELETE CASCADE
+);
+
+CREATE INDEX status_history_kaleidoid_idx ON public.status_history USING btree (kaleidoid, ts);
+
+CREATE INDEX tarascope_status_idx ON public.tarascope USING btree (status);
+
+CREATE VIEW public.showcase AS
+ SELECT concat(id, '/video.mp4') AS video,
+    concat(id, '/video.gif') AS gif,
+    COALESCE(thumbnail, concat(id, '/frame_00000.png')) AS thumbnail,
+    poster,
+    contact_sheet,
+    "timestamp" AS ts,
+    parameters,
+    id
+   FROM public.tarascope
+  WHERE (status = 'done'::public.job_status);
+
+CREATE VIEW public.progress AS
+ SELECT t.id,
+    count(f.*) AS count,
+    ((t.parameters -> 'frames'::text) -> '_frames_max'::text) AS frame_count
+   FROM (public.tarascope t
+     JOIN public.frames f ON ((f.kaleidoid = t.id)))
+  WHERE (t.status <> 'done'::public.job_status)
+  GROUP BY t.id;
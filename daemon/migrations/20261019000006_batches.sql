@@ -0,0 +1,35 @@
+-- batches of kaleidoscopes generated from one spec, tracked and cancelled together
+
+CREATE TABLE public.batches (
+    id bigserial NOT NULL,
+    name text NOT NULL,
+    kind text NOT NULL,
+    spec jsonb NOT NULL,
+    created_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP,
+    cancelled_at timestamp without time zone,
+    CONSTRAINT batches_pk PRIMARY KEY (id),
+    CONSTRAINT batches_kind_check CHECK (kind IN ('animated', 'still'))
+);
+
+ALTER TABLE public.tarascope
+    ADD COLUMN batch_id bigint,
+    ADD CONSTRAINT tarascope_batches_fk FOREIGN KEY (batch_id) REFERENCES public.batches(id) ON DELETE SET NULL;
+
+CREATE INDEX tarascope_batch_id_idx ON public.tarascope USING btree (batch_id) WHERE (batch_id IS NOT NULL);
+
+CREATE VIEW public.batch_progress AS
+ SELECT b.id,
+    b.name,
+    b.kind,
+    b.spec,
+    b.created_at,
+    b.cancelled_at,
+    count(t.id) AS total,
+    count(t.id) FILTER (WHERE (t.status = 'queued'::public.job_status)) AS queued,
+    count(t.id) FILTER (WHERE (t.status = ANY (ARRAY['rendering'::public.job_status, 'encoding'::public.job_status, 'uploading'::public.job_status]))) AS running,
+    count(t.id) FILTER (WHERE (t.status = 'done'::public.job_status)) AS done,
+    count(t.id) FILTER (WHERE (t.status = 'failed'::public.job_status)) AS failed,
+    count(t.id) FILTER (WHERE (t.status = 'cancelled'::public.job_status)) AS cancelled
+   FROM (public.batches b
+     LEFT JOIN public.tarascope t ON ((t.batch_id = b.id)))
+  GROUP BY b.id;
@@ -0,0 +1,8 @@
+-- normalized parameters of a kaleidoscope, used to find near duplicates.
+-- rows created before are filled in by the daemon on startup
+
+ALTER TABLE public.tarascope
+    ADD COLUMN texture_index smallint,
+    ADD COLUMN features real[];
+
+CREATE INDEX tarascope_texture_index_idx ON public.tarascope USING btree (texture_index);
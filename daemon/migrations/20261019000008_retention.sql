@@ -0,0 +1,26 @@
+-- curation that protects kaleidoscopes from the retention policies,
+-- and the audit log of everything the policies deleted
+
+ALTER TABLE public.tarascope
+    ADD COLUMN starred boolean DEFAULT false NOT NULL,
+    ADD COLUMN rating smallint,
+    ADD COLUMN frames_deleted_at timestamp without time zone,
+    ADD CONSTRAINT tarascope_rating_check CHECK (rating BETWEEN 1 AND 5);
+
+-- no foreign key, the entries outlive the kaleidoscopes that got purged
+CREATE TABLE public.retention_log (
+    id bigserial NOT NULL,
+    kaleidoid uuid NOT NULL,
+    policy text NOT NULL,
+    action text NOT NULL,
+    status public.job_status NOT NULL,
+    files integer NOT NULL,
+    bytes bigint NOT NULL,
+    error text,
+    ts timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT retention_log_pk PRIMARY KEY (id)
+);
+
+CREATE INDEX retention_log_ts_idx ON public.retention_log USING btree (ts);
+
+CREATE INDEX retention_log_kaleidoid_idx ON public.retention_log USING btree (kaleidoid);
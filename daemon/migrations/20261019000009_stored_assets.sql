@@ -0,0 +1,31 @@
+-- assets of a kaleidoscope in the configured storage, with the url they are served from
+
+CREATE TABLE public.stored_assets (
+    kaleidoid uuid NOT NULL,
+    name text NOT NULL,
+    key text NOT NULL,
+    url text NOT NULL,
+    size bigint NOT NULL,
+    stored_at timestamp without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    CONSTRAINT stored_assets_pk PRIMARY KEY (kaleidoid, name),
+    CONSTRAINT stored_assets_tarascope_fk FOREIGN KEY (kaleidoid) REFERENCES public.tarascope(id) ON DELETE CASCADE
+);
+
+-- the urls fall back to the output directory served under /assets
+CREATE OR REPLACE VIEW public.showcase AS
+ SELECT concat(t.id, '/video.mp4') AS video,
+    concat(t.id, '/video.gif') AS gif,
+    COALESCE(t.thumbnail, concat(t.id, '/frame_00000.png')) AS thumbnail,
+    t.poster,
+    t.contact_sheet,
+    t."timestamp" AS ts,
+    t.parameters,
+    t.id,
+    COALESCE(v.url, concat('/assets/', t.id, '/video.mp4')) AS video_url,
+    COALESCE(g.url, concat('/assets/', t.id, '/video.gif')) AS gif_url,
+    COALESCE(th.url, concat('/assets/', COALESCE(t.thumbnail, concat(t.id, '/frame_00000.png')))) AS thumbnail_url
+   FROM (((public.tarascope t
+     LEFT JOIN public.stored_assets v ON (((v.kaleidoid = t.id) AND (v.name = 'video'::text))))
+     LEFT JOIN public.stored_assets g ON (((g.kaleidoid = t.id) AND (g.name = 'gif'::text))))
+     LEFT JOIN public.stored_assets th ON (((th.kaleidoid = t.id) AND (th.name = 'thumbnail'::text))))
+  WHERE (t.status = 'done'::public.job_status);
@@ -0,0 +1,35 @@
+-- videos that were actually encoded, the native encoder writes no mp4 and stills have no videos at all
+
+ALTER TABLE public.tarascope
+    ADD COLUMN IF NOT EXISTS video text,
+    ADD COLUMN IF NOT EXISTS gif text,
+    ADD COLUMN IF NOT EXISTS apng text;
+
+-- kaleidoscopes finished before were encoded with ffmpeg
+UPDATE public.tarascope t SET video = concat(t.id, '/video.mp4'), gif = concat(t.id, '/video.gif')
+WHERE t.status = 'done'::public.job_status
+    AND NOT EXISTS (SELECT 1 FROM public.jobs j WHERE j.kaleidoid = t.id AND j.kind = 'still');
+
+-- the video urls are NULL for formats that don't exist
+DROP VIEW IF EXISTS public.showcase;
+
+CREATE VIEW public.showcase AS
+ SELECT t.video,
+    t.gif,
+    t.apng,
+    COALESCE(t.thumbnail, concat(t.id, '/frame_00000.png')) AS thumbnail,
+    t.poster,
+    t.contact_sheet,
+    t."timestamp" AS ts,
+    t.parameters,
+    t.id,
+    COALESCE(v.url, '/assets/' || t.video) AS video_url,
+    COALESCE(g.url, '/assets/' || t.gif) AS gif_url,
+    COALESCE(a.url, '/assets/' || t.apng) AS apng_url,
+    COALESCE(th.url, concat('/assets/', COALESCE(t.thumbnail, concat(t.id, '/frame_00000.png')))) AS thumbnail_url
+   FROM ((((public.tarascope t
+     LEFT JOIN public.stored_assets v ON (((v.kaleidoid = t.id) AND (v.name = 'video'::text))))
+     LEFT JOIN public.stored_assets g ON (((g.kaleidoid = t.id) AND (g.name = 'gif'::text))))
+     LEFT JOIN public.stored_assets a ON (((a.kaleidoid = t.id) AND (a.name = 'apng'::text))))
+     LEFT JOIN public.stored_assets th ON (((th.kaleidoid = t.id) AND (th.name = 'thumbnail'::text))))
+  WHERE (t.status = 'done'::public.job_status);
@@ -0,0 +1,4 @@
+-- a cancel can reach a job before the daemon that claimed it is able to stop it,
+-- the daemon then reads the choice from the job itself
+
+ALTER TABLE public.jobs ADD COLUMN IF NOT EXISTS keep_frames boolean DEFAULT false NOT NULL;
@@ -0,0 +1,17 @@
+-- a worker counts as alive until it missed two beats of its own heartbeat interval,
+-- workers registered before the interval was recorded keep the old two minutes
+
+ALTER TABLE public.workers ADD COLUMN IF NOT EXISTS heartbeat_seconds integer DEFAULT 60 NOT NULL;
+
+CREATE OR REPLACE VIEW public.worker_status AS
+ SELECT w.name,
+    w.capabilities,
+    w.heartbeat_at,
+    (w.heartbeat_at > (CURRENT_TIMESTAMP - make_interval(secs => 2 * w.heartbeat_seconds))) AS alive,
+    j.id AS job,
+    j.kind,
+    j.kaleidoid,
+    j.claimed_at,
+    j.lease_expires_at
+   FROM (public.workers w
+     LEFT JOIN public.jobs j ON (((j.worker = w.name) AND (j.state = 'running'::text))));
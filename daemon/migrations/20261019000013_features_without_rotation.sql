@@ -0,0 +1,4 @@
+-- the rotation is no longer part of the features, the daemon computes
+-- them again on startup for every row that has none
+
+UPDATE public.tarascope SET features = NULL WHERE features IS NOT NULL;
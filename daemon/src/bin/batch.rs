@@ -0,0 +1,143 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use clap_derive::{Parser, Subcommand};
+use daemon::{
+    DEFAULT_CONFIG_PATH,
+    config::DaemonConfig,
+    database::{
+        BatchProgress, all_batches, batch_progress, cancel_batch, create_batch, init_database,
+    },
+};
+use serde_json::{Map, Value};
+use tarascope::{generator::GeneratorSpec, shader::TextureKind};
+
+/// Create, inspect and cancel batches of kaleidoscopes
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: BatchCommand,
+
+    /// Configuration file, its [duplicates] section decides which kaleidoscopes are
+    /// left out as near duplicates. tarascope.toml in the working directory if it exists
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum BatchCommand {
+    /// Queue a new batch, e.g. `batch create waves --texture wave --fixed repetition=6 --count 10`
+    Create {
+        /// Name of the batch
+        name: String,
+
+        /// Texture of every kaleidoscope, a random one each if not set
+        #[arg(short, long)]
+        texture: Option<TextureKind>,
+
+        /// Parameter shared by all kaleidoscopes as key=value, can be repeated
+        #[arg(short, long, value_parser = parse_fixed)]
+        fixed: Vec<(String, Value)>,
+
+        /// Seed of the first kaleidoscope
+        #[arg(short, long, default_value_t = 0)]
+        seed: u64,
+
+        /// Number of kaleidoscopes
+        #[arg(short, long)]
+        count: u32,
+
+        /// Render stills instead of animations
+        #[arg(long)]
+        still: bool,
+    },
+
+    /// Show all batches
+    List,
+
+    /// Show the progress of a batch
+    Status { id: i64 },
+
+    /// Cancel the queued and running kaleidoscopes of a batch
+    Cancel {
+        id: i64,
+
+        /// Keep the frames that were already rendered
+        #[arg(long)]
+        keep_frames: bool,
+    },
+}
+
+fn parse_fixed(s: &str) -> Result<(String, Value), String> {
+    let (key, value) = s.split_once('=').ok_or(format!("expected key=value, got {}", s))?;
+    let value: Value = serde_json::from_str(value).map_err(|e| format!("invalid value {}: {}", value, e))?;
+    Ok((String::from(key), value))
+}
+
+fn print_progress(batch: &BatchProgress) {
+    println!(
+        "{:>5}  {:<24} {:<8} {:>4}/{:<4} done, {} queued, {} running, {} failed, {} cancelled{}",
+        batch.id,
+        batch.name,
+        batch.kind,
+        batch.done,
+        batch.total,
+        batch.queued,
+        batch.running,
+        batch.failed,
+        batch.cancelled,
+        if batch.cancelled_at.is_some() { " (cancelled)" } else { "" }
+    );
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let _ = dotenv::dotenv().ok();
+    let args = Args::parse();
+
+    let pool = init_database().await?;
+    match args.command {
+        BatchCommand::Create {
+            name,
+            texture,
+            fixed,
+            seed,
+            count,
+            still,
+        } => {
+            let spec = GeneratorSpec {
+                texture,
+                fixed: fixed.into_iter().collect::<Map<String, Value>>(),
+                seed,
+                count,
+            };
+            let config = match &args.config {
+                Some(path) => DaemonConfig::load(path, true)?,
+                None => DaemonConfig::load(Path::new(DEFAULT_CONFIG_PATH), false)?,
+            };
+            let batch = create_batch(&pool, &name, still, &spec, &config.duplicates).await?;
+            println!(
+                "created batch {} with {} kaleidoscopes, {} near duplicates left out",
+                batch.id, batch.queued, batch.duplicates
+            );
+        }
+        BatchCommand::List => {
+            for batch in all_batches(&pool).await? {
+                print_progress(&batch);
+            }
+        }
+        BatchCommand::Status { id } => match batch_progress(&pool, id).await? {
+            Some(batch) => print_progress(&batch),
+            None => return Err(format!("there is no batch {}", id).into()),
+        },
+        BatchCommand::Cancel { id, keep_frames } => match cancel_batch(&pool, id, keep_frames).await? {
+            Some(n) => println!("cancelled {} kaleidoscopes of batch {}", n, id),
+            None => return Err(format!("there is no batch {}", id).into()),
+        },
+    }
+    Ok(())
+}
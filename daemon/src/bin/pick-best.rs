@@ -1,7 +1,6 @@
 use std::error::Error;
 
-use daemon::database::{init_database, insert_new_parameterized_job, todays_done_jobs};
-use tarascope::shader::KaleidoArgs;
+use daemon::database::{init_database, todays_done_jobs};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -0,0 +1,126 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use clap_derive::{Parser, Subcommand};
+use daemon::{
+    DEFAULT_CONFIG_PATH,
+    config::DaemonConfig,
+    database::{connect_database, retention_log},
+    retention::{Retention, format_bytes},
+    storage::AssetStore,
+};
+
+/// Report, apply and audit the retention policies of the daemon config
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: RetentionCommand,
+
+    /// Configuration file, tarascope.toml in the working directory if it exists
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Overrides the output directory of the configuration
+    #[arg(short, long)]
+    output_dir: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum RetentionCommand {
+    /// Show what the policies would delete right now, without deleting anything
+    Report {
+        /// List every kaleidoscope instead of the totals only
+        #[arg(short, long)]
+        verbose: bool,
+    },
+
+    /// Apply the policies once, regardless of their dry_run setting
+    Apply,
+
+    /// Show what the policies deleted, newest first
+    Log {
+        #[arg(short, long, default_value_t = 50)]
+        limit: i64,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let _ = dotenv::dotenv().ok();
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => DaemonConfig::load(path, true)?,
+        None => DaemonConfig::load(Path::new(DEFAULT_CONFIG_PATH), false)?,
+    };
+    if let Some(dir) = args.output_dir {
+        config.output.directory = dir;
+    }
+    config.validate()?;
+
+    let pool = connect_database(&config.database).await?;
+    let dry_run = match args.command {
+        RetentionCommand::Report { .. } => true,
+        RetentionCommand::Apply => false,
+        RetentionCommand::Log { limit } => {
+            for entry in retention_log(&pool, limit).await? {
+                println!(
+                    "{}  {:<16} {:<13} {} ({:?}) {} files, {}{}",
+                    entry.ts.format("%Y-%m-%d %H:%M:%S"),
+                    entry.policy,
+                    entry.action,
+                    entry.kaleidoid,
+                    entry.status,
+                    entry.files,
+                    format_bytes(entry.bytes as u64),
+                    entry.error.map(|e| format!(", failed: {}", e)).unwrap_or_default()
+                );
+            }
+            return Ok(());
+        }
+    };
+    let verbose = matches!(args.command, RetentionCommand::Report { verbose: true });
+
+    if config.retention.policies.is_empty() {
+        println!("no retention policies configured");
+        return Ok(());
+    }
+
+    let retention = Retention::new(
+        pool,
+        config.output.directory.clone(),
+        AssetStore::new(&config.storage, &config.output.directory)?,
+        config.retention.clone(),
+    );
+    for report in retention.run(dry_run).await {
+        println!(
+            "{} ({}): {} {} files, {} of {} kaleidoscopes{}",
+            report.policy,
+            report.action.as_str(),
+            if dry_run { "would delete" } else { "deleted" },
+            report.files(),
+            format_bytes(report.bytes()),
+            report.items.len(),
+            match report.errors() {
+                0 => String::new(),
+                n => format!(", {} failed", n),
+            }
+        );
+        for item in report.items.iter().filter(|i| verbose || i.error.is_some()) {
+            println!(
+                "    {} {:?} since {}: {} files, {}{}",
+                item.kaleidoscope.id,
+                item.kaleidoscope.status,
+                item.kaleidoscope.since.format("%Y-%m-%d"),
+                item.files,
+                format_bytes(item.bytes),
+                item.error.as_ref().map(|e| format!(", {}", e)).unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
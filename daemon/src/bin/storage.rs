@@ -0,0 +1,91 @@
+use std::{
+    env::temp_dir,
+    error::Error,
+    fs::write,
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use clap_derive::{Parser, Subcommand};
+use daemon::{
+    DEFAULT_CONFIG_PATH,
+    config::DaemonConfig,
+    database::{connect_database, unstored_kaleidoscopes},
+    storage::AssetStore,
+};
+use tarascope::RenderJobDirectories;
+
+/// Check the configured asset storage and store kaleidoscopes that were rendered before it
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: StorageCommand,
+
+    /// Configuration file, tarascope.toml in the working directory if it exists
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Overrides the output directory of the configuration
+    #[arg(short, long)]
+    output_dir: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum StorageCommand {
+    /// Store and delete a small object to see whether the storage works
+    Check,
+
+    /// Store the assets of finished kaleidoscopes
+    Upload {
+        /// Kaleidoscopes to store
+        ids: Vec<String>,
+
+        /// Store every finished kaleidoscope that has nothing in the storage yet
+        #[arg(long, conflicts_with = "ids")]
+        missing: bool,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let _ = dotenv::dotenv().ok();
+    let args = Args::parse();
+
+    let mut config = match &args.config {
+        Some(path) => DaemonConfig::load(path, true)?,
+        None => DaemonConfig::load(Path::new(DEFAULT_CONFIG_PATH), false)?,
+    };
+    if let Some(dir) = args.output_dir {
+        config.output.directory = dir;
+    }
+    config.validate()?;
+
+    let store = AssetStore::new(&config.storage, &config.output.directory)?;
+    match args.command {
+        StorageCommand::Check => {
+            let probe = temp_dir().join("tarascope-storage-check.txt");
+            write(&probe, b"tarascope storage check\n")?;
+            let key = format!("{}storage-check.txt", config.storage.prefix);
+
+            store.storage().put(&key, &probe).await?;
+            println!("stored {}, served from {}", key, store.storage().url(&key));
+            store.storage().delete(&key).await?;
+            println!("deleted {}", key);
+            let _ = std::fs::remove_file(probe);
+        }
+        StorageCommand::Upload { ids, missing } => {
+            let pool = connect_database(&config.database).await?;
+            let ids = if missing { unstored_kaleidoscopes(&pool).await? } else { ids };
+
+            for id in ids {
+                let dirs = RenderJobDirectories::new(id.clone(), config.output.directory.clone());
+                match store.store(&pool, &dirs).await {
+                    Ok(n) => println!("stored {} assets of {}", n, id),
+                    Err(e) => println!("couldn't store {}: {}", id, e),
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,9 +1,6 @@
-use std::error::Error;
-
-use daemon::run;
+use daemon::{error::DaemonError, run};
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>>{
-    simple_logger::init()?;
-    Ok(run().await?)
+async fn main() -> Result<(), DaemonError>{
+    run().await
 }
\ No newline at end of file
@@ -1,6 +1,6 @@
 use std::error::Error;
 
-use daemon::database::{init_database, insert_new_parameterized_job, insert_new_parameterized_still_job};
+use daemon::database::{init_database, insert_new_parameterized_still_job};
 use tarascope::shader::KaleidoArgs;
 
 #[tokio::main]
@@ -0,0 +1,109 @@
+use std::{
+    error::Error,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use clap::Parser;
+use clap_derive::Parser;
+use daemon::webhooks::{EVENT_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER, sign};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Local stand-in for a webhook endpoint, prints every call it receives
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Address to listen on
+    #[arg(short, long, default_value = "127.0.0.1:9465")]
+    listen: String,
+
+    /// Secret to verify the signatures with, calls with a wrong signature get a 401
+    #[arg(short, long)]
+    secret: Option<String>,
+
+    /// Answer the first N calls with a 500 to exercise the retries
+    #[arg(short, long, default_value_t = 0)]
+    fail: usize,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let listener = TcpListener::bind(&args.listen).await?;
+    println!("waiting for webhooks on http://{}", args.listen);
+
+    let received = AtomicUsize::new(0);
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let n = received.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = handle(stream, &args, n < args.fail).await {
+            println!("broken request: {}", e);
+        }
+    }
+}
+
+async fn handle(mut stream: TcpStream, args: &Args, fail: bool) -> Result<(), Box<dyn Error>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    let header_end = loop {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err("connection closed before the headers ended".into());
+        }
+        request.extend_from_slice(&buf[..read]);
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&request[..header_end]).to_string();
+    let header = |name: &str| {
+        head.lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim().to_string())
+    };
+    let length: usize = header("Content-Length").and_then(|l| l.parse().ok()).unwrap_or(0);
+    while request.len() < header_end + length {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    let body = &request[header_end..];
+
+    println!("{}", head.lines().next().unwrap_or_default());
+    println!("event: {}", header(EVENT_HEADER).unwrap_or_default());
+
+    let verified = match (&args.secret, header(SIGNATURE_HEADER), header(TIMESTAMP_HEADER)) {
+        (None, _, _) => true,
+        (Some(secret), Some(signature), Some(timestamp)) => {
+            let timestamp: u64 = timestamp.parse().unwrap_or_default();
+            sign(secret, timestamp, body) == signature
+        }
+        (Some(_), _, _) => false,
+    };
+    match serde_json::from_slice::<Value>(body) {
+        Ok(payload) => println!("{}", serde_json::to_string_pretty(&payload)?),
+        Err(_) => println!("{}", String::from_utf8_lossy(body)),
+    }
+
+    let status = if !verified {
+        println!("signature doesn't match");
+        "401 Unauthorized"
+    } else if fail {
+        println!("failing on purpose");
+        "500 Internal Server Error"
+    } else {
+        "200 OK"
+    };
+    println!();
+
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
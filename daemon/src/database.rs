@@ -1,34 +1,50 @@
-use std::{env::var, error::Error};
-
 use chrono::{DateTime, NaiveDateTime, Utc};
+use log::info;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::{
     Pool, Postgres,
-    postgres::{PgPoolOptions, PgRow, types},
+    postgres::{PgExecutor, PgPoolOptions},
     prelude::FromRow,
 };
-use tarascope::{RenderStatus, shader::KaleidoArgs};
+use tarascope::{
+    EncodeStatus, RenderStatus, assets::JobAssets, encoder::VideoAssets, generator::GeneratorSpec,
+    shader::KaleidoArgs,
+};
+
+use crate::{
+    config::DatabaseConfig,
+    duplicates::{DuplicateConfig, find_duplicate},
+    error::DbError,
+};
 
-pub async fn init_database() -> Result<Pool<Postgres>, Box<dyn Error>> {
-    let host = var("PG_HOST").unwrap_or("localhost".to_string());
-    let username = var("PG_USER").unwrap_or("postgres".to_string());
-    let password = var("PG_PASS").unwrap_or("password".to_string());
-    let database = var("PG_DB").unwrap_or("postgres".to_string());
-    let connection_uri = format!("postgres://{}:{}@{}/{}", username, password, host, database);
+/// Connects with the default settings and the `PG_*` environment variables
+pub async fn init_database() -> Result<Pool<Postgres>, DbError> {
+    connect_database(&DatabaseConfig::from_env()).await
+}
 
+pub async fn connect_database(config: &DatabaseConfig) -> Result<Pool<Postgres>, DbError> {
     let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(connection_uri.as_str())
+        .max_connections(config.max_connections)
+        .connect(config.connection_uri().as_str())
         .await?;
 
-    println!("Connection to Database successful");
+    info!("Connection to Database successful");
     Ok(pool)
 }
 
-pub async fn all_kaleidoscopes(pool: &Pool<Postgres>) -> Result<Vec<Showcase>, Box<dyn Error>> {
+/// Applies all migrations in daemon/migrations that have not run on this database yet
+pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<(), DbError> {
+    sqlx::migrate!("./migrations").run(pool).await?;
+    info!("Database schema is up to date");
+    Ok(())
+}
+
+pub async fn all_kaleidoscopes(pool: &Pool<Postgres>) -> Result<Vec<Showcase>, DbError> {
     let d = sqlx::query_as::<_, Showcase>(
-        "SELECT id::text, video, gif, thumbnail, ts::timestamp FROM showcase ORDER BY ts DESC",
+        "SELECT id::text, video, gif, apng, thumbnail, poster, contact_sheet, ts::timestamp,
+            video_url, gif_url, apng_url, thumbnail_url
+        FROM showcase ORDER BY ts DESC",
     )
     .fetch_all(pool)
     .await?;
@@ -39,8 +55,8 @@ pub async fn all_kaleidoscopes(pool: &Pool<Postgres>) -> Result<Vec<Showcase>, B
 pub async fn single_kaleidoscopes(
     pool: &Pool<Postgres>,
     id: &String,
-) -> Result<Vec<Showcase>, Box<dyn Error>> {
-    let d = sqlx::query_as::<_, Showcase>("SELECT id::text, video, gif, thumbnail, ts::timestamp FROM showcase WHERE id = uuid($1) ORDER BY ts DESC")
+) -> Result<Vec<Showcase>, DbError> {
+    let d = sqlx::query_as::<_, Showcase>("SELECT id::text, video, gif, apng, thumbnail, poster, contact_sheet, ts::timestamp, video_url, gif_url, apng_url, thumbnail_url FROM showcase WHERE id = uuid($1) ORDER BY ts DESC")
     .bind(id)
     .fetch_all(pool)
     .await?;
@@ -50,93 +66,859 @@ pub async fn single_kaleidoscopes(
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Showcase {
-    // SELECT id, video, gif, thumbnail, ts FROM showcase ORDER BY ts DESC
+    // SELECT id, video, gif, thumbnail, poster, contact_sheet, ts FROM showcase ORDER BY ts DESC
     id: String,
-    video: String,
-    gif: String,
+    /// only the videos the encoder actually wrote are set
+    video: Option<String>,
+    gif: Option<String>,
+    apng: Option<String>,
     thumbnail: String,
+    poster: Option<String>,
+    contact_sheet: Option<String>,
     ts: NaiveDateTime,
+    /// where the assets are served from, the storage or the output directory
+    video_url: Option<String>,
+    gif_url: Option<String>,
+    apng_url: Option<String>,
+    thumbnail_url: String,
 }
 
-pub async fn trigger_generation(pool: &Pool<Postgres>) -> Result<(), Box<dyn Error>> {
+pub async fn trigger_generation(pool: &Pool<Postgres>) -> Result<(), DbError> {
     sqlx::query("NOTIFY generate_random").execute(pool).await?;
     Ok(())
 }
 
+/// Lifecycle of a kaleidoscope, mapped to the `job_status` enum in postgres
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "job_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Rendering,
+    Encoding,
+    Uploading,
+    Done,
+    Failed,
+    Cancelled,
+}
+
 pub async fn register_new_kaleidoscope(
+    pool: impl PgExecutor<'_>,
+    kargs: &KaleidoArgs,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "WITH t AS (
+            INSERT INTO public.tarascope (id, parameters, status, texture_index, features)
+            VALUES (uuid($1), json($2), $3, $4, $5)
+            RETURNING id, status
+        )
+        INSERT INTO public.status_history (kaleidoid, status) SELECT id, status FROM t",
+    )
+    .bind(kargs.get_id())
+    .bind(kargs.json().to_string())
+    .bind(JobStatus::Queued)
+    .bind(kargs.texture_index() as i16)
+    .bind(kargs.features())
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// An existing kaleidoscope close to the one looked up
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct SimilarKaleidoscope {
+    pub id: String,
+    /// normalized distance, see [`KaleidoArgs::distance`]
+    pub distance: f32,
+}
+
+/// Kaleidoscopes within `epsilon` of `kargs`, closest first. Failed and cancelled
+/// ones don't count, neither does the kaleidoscope itself.
+pub async fn similar_kaleidoscopes(
+    pool: impl PgExecutor<'_>,
+    kargs: &KaleidoArgs,
+    epsilon: f32,
+    limit: i64,
+) -> Result<Vec<SimilarKaleidoscope>, DbError> {
+    // the same distance as KaleidoArgs::distance, computed over every stored kaleidoscope
+    let similar = sqlx::query_as::<_, SimilarKaleidoscope>(
+        "SELECT id, distance FROM (
+            SELECT t.id::text AS id, sqrt(avg((CASE WHEN u.circular
+                THEN 2 * least(abs(u.a - u.b), 1 - abs(u.a - u.b))
+                ELSE u.a - u.b END) ^ 2))::real AS distance
+            FROM public.tarascope t, unnest(t.features, $2::real[], $6::boolean[]) AS u(a, b, circular)
+            WHERE t.texture_index = $1 AND t.id <> uuid($3)
+                AND t.status NOT IN ('failed', 'cancelled')
+                AND cardinality(t.features) = cardinality($2::real[])
+            GROUP BY t.id
+        ) s
+        WHERE distance <= $4
+        ORDER BY distance
+        LIMIT $5",
+    )
+    .bind(kargs.texture_index() as i16)
+    .bind(kargs.features())
+    .bind(kargs.get_id())
+    .bind(epsilon as f64)
+    .bind(limit)
+    .bind(kargs.circular_features())
+    .fetch_all(pool)
+    .await?;
+    Ok(similar)
+}
+
+/// Computes the features of kaleidoscopes that were created before they existed.
+/// Returns the number of updated rows.
+pub async fn backfill_features(pool: &Pool<Postgres>) -> Result<u64, DbError> {
+    let rows: Vec<(String, Value)> =
+        sqlx::query_as("SELECT id::text, parameters::jsonb FROM public.tarascope WHERE features IS NULL")
+            .fetch_all(pool)
+            .await?;
+
+    let mut updated = 0;
+    for (id, parameters) in rows {
+        let kargs = match KaleidoArgs::from_json(parameters) {
+            Ok(kargs) => kargs,
+            Err(e) => {
+                info!("skipping features of {}, invalid parameters: {}", id, e);
+                continue;
+            }
+        };
+        sqlx::query("UPDATE public.tarascope SET texture_index = $2, features = $3 WHERE id = uuid($1)")
+            .bind(&id)
+            .bind(kargs.texture_index() as i16)
+            .bind(kargs.features())
+            .execute(pool)
+            .await?;
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+/// Moves a kaleidoscope to `status` and records the transition in the status history,
+/// both in a single statement. A cancelled kaleidoscope stays cancelled, its job may
+/// still be running for a moment until the daemon stops it.
+pub async fn set_kaleidoscope_status(
+    pool: &Pool<Postgres>,
+    id: &String,
+    status: JobStatus,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "WITH t AS (
+            UPDATE public.tarascope SET status = $2
+            WHERE id = uuid($1) AND status <> 'cancelled' RETURNING id, status
+        )
+        INSERT INTO public.status_history (kaleidoid, status) SELECT id, status FROM t",
+    )
+    .bind(id)
+    .bind(status)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Marks a kaleidoscope as failed and keeps the reason and the end of blender's stderr,
+/// unless it was cancelled
+pub async fn set_kaleidoscope_to_failed(
+    pool: &Pool<Postgres>,
+    id: &String,
+    error: &str,
+    stderr_tail: Option<&str>,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "WITH t AS (
+            UPDATE public.tarascope SET status = $2, error = $3, stderr_tail = $4
+            WHERE id = uuid($1) AND status <> 'cancelled' RETURNING id, status
+        )
+        INSERT INTO public.status_history (kaleidoid, status) SELECT id, status FROM t",
+    )
+    .bind(id)
+    .bind(JobStatus::Failed)
+    .bind(error)
+    .bind(stderr_tail)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct StatusTransition {
+    status: JobStatus,
+    ts: NaiveDateTime,
+}
+
+/// Every status a kaleidoscope went through, oldest first
+pub async fn status_history(
     pool: &Pool<Postgres>,
     id: &String,
-    params: String,
-) -> Result<(), Box<dyn Error>> {
-    sqlx::query("INSERT INTO public.tarascope (id, parameters) VALUES (uuid($1), json($2))")
+) -> Result<Vec<StatusTransition>, DbError> {
+    let d = sqlx::query_as::<_, StatusTransition>(
+        "SELECT status, ts FROM public.status_history WHERE kaleidoid = uuid($1) ORDER BY ts, historyid",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(d)
+}
+
+pub async fn set_kaleidoscope_assets(
+    pool: &Pool<Postgres>,
+    id: &String,
+    assets: &JobAssets,
+) -> Result<(), DbError> {
+    sqlx::query("UPDATE public.tarascope SET thumbnail=$2, poster=$3, poster_frame=$4, contact_sheet=$5 WHERE id = uuid($1)")
         .bind(id)
-        .bind(params)
+        .bind(&assets.thumbnail)
+        .bind(&assets.poster)
+        .bind(assets.poster_frame)
+        .bind(&assets.contact_sheet)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn set_kaleidoscope_to_waiting(
+/// Records the videos the encoder wrote, formats it couldn't produce are cleared
+pub async fn set_kaleidoscope_videos(
     pool: &Pool<Postgres>,
     id: &String,
-) -> Result<(), Box<dyn Error>> {
-    sqlx::query("UPDATE public.tarascope SET status=1 WHERE id = uuid($1)")
+    videos: &VideoAssets,
+) -> Result<(), DbError> {
+    sqlx::query("UPDATE public.tarascope SET video=$2, gif=$3, apng=$4 WHERE id = uuid($1)")
         .bind(id)
+        .bind(&videos.video)
+        .bind(&videos.gif)
+        .bind(&videos.apng)
         .execute(pool)
         .await?;
     Ok(())
 }
-async fn set_kaleidoscope_to_running(
+
+pub async fn insert_frame(
     pool: &Pool<Postgres>,
-    id: &String,
-) -> Result<(), Box<dyn Error>> {
-    sqlx::query("UPDATE public.tarascope SET status=2 WHERE id = uuid($1)")
-        .bind(id)
+    update: RenderStatus,
+) -> Result<(), DbError> {
+    sqlx::query("INSERT INTO public.frames (kaleidoid, frame_count) VALUES (uuid($1), $2)")
+        .bind(update.id)
+        .bind(update.frame)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-async fn set_kaleidoscope_to_failed(
+pub async fn set_encode_progress(
+    pool: &Pool<Postgres>,
+    update: EncodeStatus,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "UPDATE public.tarascope SET encode_target=$2, encode_progress=$3 WHERE id = uuid($1)",
+    )
+    .bind(update.id)
+    .bind(update.target)
+    .bind(update.progress)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct JobProgress {
+    id: String,
+    status: JobStatus,
+    frames: i64,
+    frames_max: Option<i32>,
+    encode_target: Option<String>,
+    encode_progress: Option<f32>,
+}
+
+pub async fn job_progress(
+    pool: &Pool<Postgres>,
+    id: &String,
+) -> Result<Option<JobProgress>, DbError> {
+    let d = sqlx::query_as::<_, JobProgress>(
+        "SELECT t.id::text, t.status, count(f.frameid) AS frames,
+            (t.parameters -> 'frames' ->> '_frames_max')::integer AS frames_max,
+            t.encode_target, t.encode_progress
+        FROM public.tarascope t
+        LEFT JOIN public.frames f ON f.kaleidoid = t.id
+        WHERE t.id = uuid($1)
+        GROUP BY t.id",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(d)
+}
+
+/// Job kinds as stored in the `kind` column of the jobs table
+pub const JOB_KIND_RANDOM: &str = "random_animated";
+pub const JOB_KIND_ANIMATED: &str = "animated";
+pub const JOB_KIND_STILL: &str = "still";
+
+#[derive(Debug, Clone, FromRow)]
+pub struct QueuedJob {
+    pub id: i64,
+    pub kind: String,
+    pub kaleidoid: Option<String>,
+    /// seconds between creating and claiming the job
+    pub waited: f64,
+}
+
+/// Default priority of a job kind, higher priorities get rendered first.
+/// Interactive stills come before user animations, which come before random filler.
+pub fn default_priority(kind: &str) -> i32 {
+    match kind {
+        JOB_KIND_STILL => 100,
+        JOB_KIND_ANIMATED => 50,
+        _ => 0,
+    }
+}
+
+/// Adds a pending job with the default priority of its kind.
+/// Does nothing if the kaleidoscope already has a job.
+pub async fn enqueue_job(
+    pool: impl PgExecutor<'_>,
+    kind: &str,
+    id: Option<&String>,
+) -> Result<(), DbError> {
+    enqueue_job_with_priority(pool, kind, id, default_priority(kind)).await
+}
+
+pub async fn enqueue_job_with_priority(
+    pool: impl PgExecutor<'_>,
+    kind: &str,
+    id: Option<&String>,
+    priority: i32,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT INTO public.jobs (kind, kaleidoid, priority) VALUES ($1, uuid($2), $3) ON CONFLICT (kaleidoid) DO NOTHING",
+    )
+    .bind(kind)
+    .bind(id)
+    .bind(priority)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Snapshot of the queue the scheduler decides on
+#[derive(Debug, FromRow)]
+pub struct SchedulerCounts {
+    /// user jobs (animations and stills) waiting to be claimed
+    pub user_pending: i64,
+    /// random jobs that are pending or running
+    pub random_queued: i64,
+    /// random jobs created since the start of the scheduler's day
+    pub random_today: i64,
+}
+
+/// Advisory lock the daemons take while they schedule, so that only one of them does at a time
+static SCHEDULER_LOCK: i64 = 0x7461_7261_7363_6f70;
+
+/// Queues the random jobs the scheduler asks for. `wanted` decides on a snapshot of the
+/// queue how many jobs to add. The snapshot and the inserts run in one transaction that
+/// holds [`SCHEDULER_LOCK`], so the schedulers of several daemons never count the same
+/// jobs twice. `today` is the start of the day the scheduler counts the random jobs of,
+/// taken from its own clock. Returns the number of queued jobs, `None` if another daemon
+/// holds the lock.
+pub async fn schedule_random_jobs(
+    pool: &Pool<Postgres>,
+    today: DateTime<Utc>,
+    wanted: impl FnOnce(&SchedulerCounts) -> i64,
+) -> Result<Option<i64>, DbError> {
+    let mut tx = pool.begin().await?;
+    let (locked,): (bool,) = sqlx::query_as("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(SCHEDULER_LOCK)
+        .fetch_one(&mut *tx)
+        .await?;
+    if !locked {
+        return Ok(None);
+    }
+
+    let counts = sqlx::query_as::<_, SchedulerCounts>(
+        "SELECT
+            count(*) FILTER (WHERE state = 'pending' AND kind <> $1) AS user_pending,
+            count(*) FILTER (WHERE state IN ('pending', 'running') AND kind = $1) AS random_queued,
+            count(*) FILTER (WHERE kind = $1 AND created_at >= $2) AS random_today
+        FROM public.jobs",
+    )
+    .bind(JOB_KIND_RANDOM)
+    .bind(today)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let wanted = wanted(&counts).max(0);
+    for _ in 0..wanted {
+        sqlx::query("INSERT INTO public.jobs (kind, priority) VALUES ($1, $2)")
+            .bind(JOB_KIND_RANDOM)
+            .bind(default_priority(JOB_KIND_RANDOM))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(Some(wanted))
+}
+
+/// Claims the pending job with the highest priority (oldest first) of one of the given kinds
+/// for `worker`, together with a lease that runs out after `lease` seconds. Running jobs whose
+/// lease ran out are claimed again, their worker died. That counts as a failed attempt, jobs
+/// without attempts left are given up by [`fail_abandoned_jobs`] instead. The workers of one
+/// daemon share a name, so they never take over each other's jobs. Random jobs are held back
+/// as long as any user job is waiting. Concurrent claims skip rows that are locked by another
+/// transaction, so every job is handed out exactly once.
+pub async fn claim_next_job(
+    pool: &Pool<Postgres>,
+    kinds: &[&str],
+    worker: &str,
+    lease: f64,
+    max_attempts: i32,
+) -> Result<Option<QueuedJob>, DbError> {
+    let job = sqlx::query_as::<_, QueuedJob>(
+        "UPDATE public.jobs SET state = 'running', claimed_at = CURRENT_TIMESTAMP, worker = $2,
+            lease_expires_at = CURRENT_TIMESTAMP + $3 * interval '1 second',
+            attempts = CASE WHEN state = 'running' THEN attempts + 1 ELSE attempts END
+        WHERE id = (
+            SELECT id FROM public.jobs
+            WHERE kind = ANY($1)
+                AND ((state = 'pending' AND (next_attempt_at IS NULL OR next_attempt_at <= CURRENT_TIMESTAMP))
+                    OR (state = 'running' AND lease_expires_at < CURRENT_TIMESTAMP AND worker <> $2
+                        AND attempts + 1 < $5))
+                AND (kind <> $4 OR NOT EXISTS (
+                    SELECT 1 FROM public.jobs u WHERE u.state = 'pending' AND u.kind <> $4
+                ))
+            ORDER BY priority DESC, id
+            FOR UPDATE SKIP LOCKED
+            LIMIT 1
+        )
+        RETURNING id, kind, kaleidoid::text,
+            EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - created_at)::float8 AS waited",
+    )
+    .bind(kinds)
+    .bind(worker)
+    .bind(lease)
+    .bind(JOB_KIND_RANDOM)
+    .bind(max_attempts)
+    .fetch_optional(pool)
+    .await?;
+    Ok(job)
+}
+
+/// A job given up because its worker died during the last attempt
+#[derive(Debug, FromRow)]
+pub struct AbandonedJob {
+    pub kind: String,
+    pub kaleidoid: Option<String>,
+}
+
+/// Gives up the running jobs of the given kinds whose lease ran out on their last attempt,
+/// together with their kaleidoscopes, instead of handing them out again. Returns the jobs
+/// that were given up.
+pub async fn fail_abandoned_jobs(
+    pool: &Pool<Postgres>,
+    kinds: &[&str],
+    worker: &str,
+    max_attempts: i32,
+) -> Result<Vec<AbandonedJob>, DbError> {
+    let jobs = sqlx::query_as::<_, AbandonedJob>(
+        "WITH j AS (
+            UPDATE public.jobs SET state = 'failed', attempts = attempts + 1, last_error = $4,
+                finished_at = CURRENT_TIMESTAMP, lease_expires_at = NULL
+            WHERE id IN (
+                SELECT id FROM public.jobs
+                WHERE kind = ANY($1) AND state = 'running' AND lease_expires_at < CURRENT_TIMESTAMP
+                    AND worker <> $2 AND attempts + 1 >= $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING kind, kaleidoid
+        ), t AS (
+            UPDATE public.tarascope SET status = $5, error = $4
+            WHERE id IN (SELECT kaleidoid FROM j) AND status <> 'cancelled' RETURNING id, status
+        ), h AS (
+            INSERT INTO public.status_history (kaleidoid, status) SELECT id, status FROM t
+        )
+        SELECT kind, kaleidoid::text FROM j",
+    )
+    .bind(kinds)
+    .bind(worker)
+    .bind(max_attempts)
+    .bind("the worker rendering it stopped renewing its lease")
+    .bind(JobStatus::Failed)
+    .fetch_all(pool)
+    .await?;
+    Ok(jobs)
+}
+
+/// Checks that the database answers
+pub async fn ping(pool: &Pool<Postgres>) -> Result<(), DbError> {
+    sqlx::query("SELECT 1").execute(pool).await?;
+    Ok(())
+}
+
+/// Number of jobs by kind and state
+pub async fn job_counts(pool: &Pool<Postgres>) -> Result<Vec<(String, String, i64)>, DbError> {
+    let counts: Vec<(String, String, i64)> =
+        sqlx::query_as("SELECT kind, state, count(*) FROM public.jobs GROUP BY kind, state")
+            .fetch_all(pool)
+            .await?;
+    Ok(counts)
+}
+
+/// Extends the lease of a running job, as long as `worker` still owns it.
+/// Returns false if the job was taken over by another worker or isn't running anymore.
+pub async fn renew_lease(
     pool: &Pool<Postgres>,
+    job_id: i64,
+    worker: &str,
+    lease: f64,
+) -> Result<bool, DbError> {
+    let res = sqlx::query(
+        "UPDATE public.jobs SET lease_expires_at = CURRENT_TIMESTAMP + $3 * interval '1 second'
+        WHERE id = $1 AND worker = $2 AND state = 'running'",
+    )
+    .bind(job_id)
+    .bind(worker)
+    .bind(lease)
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Puts the jobs a worker was running before it restarted back into the queue,
+/// instead of waiting for their leases to run out
+pub async fn release_worker_jobs(pool: &Pool<Postgres>, worker: &str) -> Result<u64, DbError> {
+    let res = sqlx::query(
+        "UPDATE public.jobs SET state = 'pending', claimed_at = NULL, worker = NULL, lease_expires_at = NULL
+        WHERE state = 'running' AND worker = $1",
+    )
+    .bind(worker)
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+/// Links a random job to the kaleidoscope that was generated for it
+pub async fn set_job_kaleidoscope(
+    pool: &Pool<Postgres>,
+    job_id: i64,
     id: &String,
-) -> Result<(), Box<dyn Error>> {
-    sqlx::query("UPDATE public.tarascope SET status=4 WHERE id = uuid($1)")
+) -> Result<(), DbError> {
+    sqlx::query("UPDATE public.jobs SET kaleidoid = uuid($2) WHERE id = $1")
+        .bind(job_id)
         .bind(id)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn set_kaleidoscope_to_done(
+/// Marks a job and its kaleidoscope as done, both in a single statement. Does nothing and
+/// returns false if the lease ran out and another worker took over, or if the job got
+/// cancelled in the meantime.
+pub async fn finish_job(
+    pool: &Pool<Postgres>,
+    job_id: i64,
+    worker: &str,
+) -> Result<bool, DbError> {
+    let (finished,): (i64,) = sqlx::query_as(
+        "WITH j AS (
+            UPDATE public.jobs SET state = 'done', finished_at = CURRENT_TIMESTAMP, lease_expires_at = NULL
+            WHERE id = $1 AND worker = $2 AND state = 'running' RETURNING kaleidoid
+        ), t AS (
+            UPDATE public.tarascope SET status = $3
+            WHERE id IN (SELECT kaleidoid FROM j) AND status <> 'cancelled' RETURNING id, status
+        ), h AS (
+            INSERT INTO public.status_history (kaleidoid, status) SELECT id, status FROM t
+        )
+        SELECT count(*) FROM j",
+    )
+    .bind(job_id)
+    .bind(worker)
+    .bind(JobStatus::Done)
+    .fetch_one(pool)
+    .await?;
+    Ok(finished > 0)
+}
+
+/// Records a failed attempt of a job. While the job has attempts left it goes back into
+/// the queue, delayed by `backoff` seconds doubled for every earlier attempt.
+/// Returns whether the job will be retried, `None` if the job wasn't running for
+/// `worker` anymore, e.g. because it was cancelled in the meantime.
+pub async fn fail_job(
+    pool: &Pool<Postgres>,
+    job_id: i64,
+    worker: &str,
+    error: &str,
+    max_attempts: i32,
+    backoff: f64,
+) -> Result<Option<bool>, DbError> {
+    let retry: Option<(bool,)> = sqlx::query_as(
+        "UPDATE public.jobs SET attempts = attempts + 1, last_error = $3,
+            state = CASE WHEN attempts + 1 < $4 THEN 'pending' ELSE 'failed' END,
+            next_attempt_at = CASE WHEN attempts + 1 < $4
+                THEN CURRENT_TIMESTAMP + $5 * power(2, attempts) * interval '1 second' END,
+            finished_at = CASE WHEN attempts + 1 < $4 THEN NULL ELSE CURRENT_TIMESTAMP END,
+            claimed_at = NULL, worker = NULL, lease_expires_at = NULL
+        WHERE id = $1 AND worker = $2 AND state = 'running'
+        RETURNING state = 'pending'",
+    )
+    .bind(job_id)
+    .bind(worker)
+    .bind(error)
+    .bind(max_attempts)
+    .bind(backoff)
+    .fetch_optional(pool)
+    .await?;
+    Ok(retry.map(|r| r.0))
+}
+
+/// Kaleidoscope a job renders, if it has one yet
+pub async fn job_kaleidoscope(
+    pool: &Pool<Postgres>,
+    job_id: i64,
+) -> Result<Option<String>, DbError> {
+    let q: Option<(Option<String>,)> =
+        sqlx::query_as("SELECT kaleidoid::text FROM public.jobs WHERE id = $1")
+            .bind(job_id)
+            .fetch_optional(pool)
+            .await?;
+    Ok(q.and_then(|q| q.0))
+}
+
+/// Job of a kaleidoscope, if it has one
+pub async fn kaleidoscope_job(
+    pool: &Pool<Postgres>,
+    id: &String,
+) -> Result<Option<i64>, DbError> {
+    let q: Option<(i64,)> = sqlx::query_as("SELECT id FROM public.jobs WHERE kaleidoid = uuid($1)")
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(q.map(|q| q.0))
+}
+
+/// Payload of the `cancel_job` channel
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CancelRequest {
+    pub id: String,
+    /// keep the frames a running job has rendered so far instead of deleting them
+    pub keep_frames: bool,
+}
+
+/// Takes the job of a kaleidoscope out of the queue and tells the daemons to stop it
+/// if it is already running. Returns false if the job was already finished.
+pub async fn cancel_kaleidoscope(
     pool: &Pool<Postgres>,
     id: &String,
-) -> Result<(), Box<dyn Error>> {
-    sqlx::query("UPDATE public.tarascope SET status=3 WHERE id = uuid($1)")
+    keep_frames: bool,
+) -> Result<bool, DbError> {
+    let job: Option<(i64,)> = sqlx::query_as(
+        "UPDATE public.jobs SET state = 'cancelled', finished_at = CURRENT_TIMESTAMP,
+            next_attempt_at = NULL, lease_expires_at = NULL, keep_frames = $2
+        WHERE kaleidoid = uuid($1) AND state IN ('pending', 'running')
+        RETURNING id",
+    )
+    .bind(id)
+    .bind(keep_frames)
+    .fetch_optional(pool)
+    .await?;
+    if job.is_none() {
+        return Ok(false);
+    }
+
+    set_kaleidoscope_status(pool, id, JobStatus::Cancelled).await?;
+
+    let request = CancelRequest {
+        id: id.clone(),
+        keep_frames,
+    };
+    sqlx::query("SELECT pg_notify('cancel_job', $1)")
+        .bind(serde_json::to_string(&request)?)
+        .execute(pool)
+        .await?;
+    Ok(true)
+}
+
+/// Whether a job was cancelled, and if so whether its frames are kept
+pub async fn job_cancelled(pool: &Pool<Postgres>, job_id: i64) -> Result<Option<bool>, DbError> {
+    let q: Option<(bool,)> =
+        sqlx::query_as("SELECT keep_frames FROM public.jobs WHERE id = $1 AND state = 'cancelled'")
+            .bind(job_id)
+            .fetch_optional(pool)
+            .await?;
+    Ok(q.map(|q| q.0))
+}
+
+/// Forgets the rendered frames of a kaleidoscope
+pub async fn delete_frames(pool: &Pool<Postgres>, id: &String) -> Result<(), DbError> {
+    sqlx::query("DELETE FROM public.frames WHERE kaleidoid = uuid($1)")
         .bind(id)
         .execute(pool)
         .await?;
     Ok(())
 }
 
-pub async fn insert_frame(
+/// Registers a worker or updates its capabilities if it was registered before.
+/// `heartbeat_seconds` is how often it beats, the `worker_status` view derives from it
+/// whether the worker is still alive.
+pub async fn register_worker(
     pool: &Pool<Postgres>,
-    update: RenderStatus,
-) -> Result<(), Box<dyn Error>> {
-    sqlx::query("INSERT INTO public.frames (kaleidoid, frame_count) VALUES (uuid($1), $2)")
-        .bind(update.id)
-        .bind(update.frame)
+    name: &str,
+    capabilities: &[String],
+    heartbeat_seconds: u64,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT INTO public.workers (name, capabilities, heartbeat_seconds) VALUES ($1, $2, $3)
+        ON CONFLICT (name) DO UPDATE SET capabilities = $2, heartbeat_seconds = $3,
+            started_at = CURRENT_TIMESTAMP, heartbeat_at = CURRENT_TIMESTAMP",
+    )
+    .bind(name)
+    .bind(capabilities)
+    .bind(heartbeat_seconds as i32)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn worker_heartbeat(pool: &Pool<Postgres>, name: &str) -> Result<(), DbError> {
+    sqlx::query("UPDATE public.workers SET heartbeat_at = CURRENT_TIMESTAMP WHERE name = $1")
+        .bind(name)
         .execute(pool)
         .await?;
     Ok(())
 }
 
+/// What a webhook reports about a kaleidoscope
+#[derive(Debug, FromRow)]
+pub struct WebhookSubject {
+    pub id: String,
+    pub status: String,
+    pub parameters: Value,
+    pub video: Option<String>,
+    pub gif: Option<String>,
+    pub thumbnail: Option<String>,
+    pub poster: Option<String>,
+    pub contact_sheet: Option<String>,
+    pub error: Option<String>,
+}
+
+pub async fn webhook_subject(
+    pool: &Pool<Postgres>,
+    id: &String,
+) -> Result<Option<WebhookSubject>, DbError> {
+    let subject = sqlx::query_as::<_, WebhookSubject>(
+        "SELECT id::text, status::text, parameters, video, gif, thumbnail, poster, contact_sheet, error
+        FROM public.tarascope WHERE id = uuid($1)",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(subject)
+}
+
+/// Records a webhook call, it gets sent by the next free dispatcher
+pub async fn enqueue_webhook(
+    pool: &Pool<Postgres>,
+    id: &String,
+    event: &str,
+    url: &str,
+    payload: &Value,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT INTO public.webhook_deliveries (kaleidoid, event, url, payload) VALUES (uuid($1), $2, $3, $4)",
+    )
+    .bind(id)
+    .bind(event)
+    .bind(url)
+    .bind(payload)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub event: String,
+    pub url: String,
+    pub payload: Value,
+    /// attempts including the one that is about to be made
+    pub attempts: i32,
+}
+
+/// Takes up to `limit` due webhook calls. They are hidden from other dispatchers
+/// for `lease` seconds, a call that is neither delivered nor failed by then is sent again.
+pub async fn claim_webhook_deliveries(
+    pool: &Pool<Postgres>,
+    limit: i64,
+    lease: f64,
+) -> Result<Vec<WebhookDelivery>, DbError> {
+    let deliveries = sqlx::query_as::<_, WebhookDelivery>(
+        "UPDATE public.webhook_deliveries
+        SET attempts = attempts + 1, next_attempt_at = CURRENT_TIMESTAMP + $2 * interval '1 second'
+        WHERE id IN (
+            SELECT id FROM public.webhook_deliveries
+            WHERE state = 'pending' AND next_attempt_at <= CURRENT_TIMESTAMP
+            ORDER BY next_attempt_at
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, event, url, payload, attempts",
+    )
+    .bind(limit)
+    .bind(lease)
+    .fetch_all(pool)
+    .await?;
+    Ok(deliveries)
+}
+
+pub async fn webhook_delivered(
+    pool: &Pool<Postgres>,
+    delivery: i64,
+    status: i32,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "UPDATE public.webhook_deliveries
+        SET state = 'delivered', response_status = $2, last_error = NULL, delivered_at = CURRENT_TIMESTAMP
+        WHERE id = $1",
+    )
+    .bind(delivery)
+    .bind(status)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Records a failed webhook call. It is sent again in `retry_in` seconds,
+/// or given up if there is no retry left.
+pub async fn webhook_failed(
+    pool: &Pool<Postgres>,
+    delivery: i64,
+    status: Option<i32>,
+    error: &str,
+    retry_in: Option<f64>,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "UPDATE public.webhook_deliveries
+        SET response_status = $2, last_error = $3,
+            state = CASE WHEN $4::float8 IS NULL THEN 'failed' ELSE 'pending' END,
+            next_attempt_at = CURRENT_TIMESTAMP + COALESCE($4, 0) * interval '1 second'
+        WHERE id = $1",
+    )
+    .bind(delivery)
+    .bind(status)
+    .bind(error)
+    .bind(retry_in)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
 pub async fn insert_new_parameterized_job(
     pool: &Pool<Postgres>,
     kargs: KaleidoArgs,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), DbError> {
     let id = kargs.get_id();
-    register_new_kaleidoscope(pool, &id, kargs.json().to_string()).await?;
+    register_new_kaleidoscope(pool, &kargs).await?;
+    enqueue_job(pool, JOB_KIND_ANIMATED, Some(&id)).await?;
 
     sqlx::query("SELECT pg_notify('queue_parameters', $1)")
         .bind(id)
@@ -148,9 +930,10 @@ pub async fn insert_new_parameterized_job(
 pub async fn insert_new_parameterized_still_job(
     pool: &Pool<Postgres>,
     kargs: KaleidoArgs,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), DbError> {
     let id = kargs.get_id();
-    register_new_kaleidoscope(pool, &id, kargs.json().to_string()).await?;
+    register_new_kaleidoscope(pool, &kargs).await?;
+    enqueue_job(pool, JOB_KIND_STILL, Some(&id)).await?;
 
     sqlx::query("SELECT pg_notify('queue_still', $1)")
         .bind(id)
@@ -159,25 +942,414 @@ pub async fn insert_new_parameterized_still_job(
     Ok(())
 }
 
+/// A batch with the state of its kaleidoscopes
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct BatchProgress {
+    pub id: i64,
+    pub name: String,
+    pub kind: String,
+    pub spec: Value,
+    pub created_at: Option<NaiveDateTime>,
+    pub cancelled_at: Option<NaiveDateTime>,
+    pub total: i64,
+    pub queued: i64,
+    /// rendering, encoding or uploading
+    pub running: i64,
+    pub done: i64,
+    pub failed: i64,
+    pub cancelled: i64,
+}
+
+/// A batch that was just created
+#[derive(Debug, Serialize)]
+pub struct CreatedBatch {
+    pub id: i64,
+    /// kaleidoscopes that were queued
+    pub queued: usize,
+    /// kaleidoscopes that were left out as near duplicates
+    pub duplicates: usize,
+}
+
+/// Creates a batch and queues a job for every kaleidoscope its spec generates,
+/// all or nothing. Kaleidoscopes that nearly match an existing one, or one of the
+/// same batch, are left out instead of rolled again, the same spec still generates
+/// the same parameters.
+pub async fn create_batch(
+    pool: &Pool<Postgres>,
+    name: &str,
+    still: bool,
+    spec: &GeneratorSpec,
+    duplicates: &DuplicateConfig,
+) -> Result<CreatedBatch, DbError> {
+    // invalid specs fail before anything is written
+    let jobs = spec.generate().map_err(|e| DbError::InvalidParameters(e.to_string()))?;
+    let (kind, channel) = if still {
+        (JOB_KIND_STILL, "queue_still")
+    } else {
+        (JOB_KIND_ANIMATED, "queue_parameters")
+    };
+
+    let mut tx = pool.begin().await?;
+    let (batch,): (i64,) =
+        sqlx::query_as("INSERT INTO public.batches (name, kind, spec) VALUES ($1, $2, $3) RETURNING id")
+            .bind(name)
+            .bind(kind)
+            .bind(serde_json::to_value(spec)?)
+            .fetch_one(&mut *tx)
+            .await?;
+
+    let mut last = None;
+    let mut skipped = 0;
+    for kargs in jobs {
+        let id = kargs.get_id();
+        // earlier kaleidoscopes of the batch are visible inside the transaction
+        if let Some(similar) = find_duplicate(&mut *tx, &kargs, duplicates).await? {
+            info!("leaving {} out of batch {}, it is {:.3} from {}", id, name, similar.distance, similar.id);
+            skipped += 1;
+            continue;
+        }
+        register_new_kaleidoscope(&mut *tx, &kargs).await?;
+        sqlx::query("UPDATE public.tarascope SET batch_id = $2 WHERE id = uuid($1)")
+            .bind(&id)
+            .bind(batch)
+            .execute(&mut *tx)
+            .await?;
+        enqueue_job(&mut *tx, kind, Some(&id)).await?;
+        last = Some(id);
+    }
+
+    // the jobs are in the table already, a single notification wakes the workers.
+    // It is only delivered once the transaction commits.
+    let Some(id) = last else {
+        return Err(DbError::InvalidParameters(String::from(
+            "every kaleidoscope of the batch nearly matches an existing one",
+        )));
+    };
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(channel)
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    let queued = spec.count as usize - skipped;
+    info!("created batch {} ({}) with {} kaleidoscopes, {} duplicates left out", batch, name, queued, skipped);
+    Ok(CreatedBatch {
+        id: batch,
+        queued,
+        duplicates: skipped,
+    })
+}
+
+pub async fn all_batches(pool: &Pool<Postgres>) -> Result<Vec<BatchProgress>, DbError> {
+    let batches = sqlx::query_as::<_, BatchProgress>(
+        "SELECT * FROM public.batch_progress ORDER BY created_at DESC, id DESC",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(batches)
+}
+
+pub async fn batch_progress(
+    pool: &Pool<Postgres>,
+    batch: i64,
+) -> Result<Option<BatchProgress>, DbError> {
+    let progress = sqlx::query_as::<_, BatchProgress>("SELECT * FROM public.batch_progress WHERE id = $1")
+        .bind(batch)
+        .fetch_optional(pool)
+        .await?;
+    Ok(progress)
+}
+
+/// Finished kaleidoscopes of a batch
+pub async fn batch_kaleidoscopes(
+    pool: &Pool<Postgres>,
+    batch: i64,
+) -> Result<Vec<Showcase>, DbError> {
+    let d = sqlx::query_as::<_, Showcase>(
+        "SELECT s.id::text, s.video, s.gif, s.apng, s.thumbnail, s.poster, s.contact_sheet, s.ts::timestamp,
+            s.video_url, s.gif_url, s.apng_url, s.thumbnail_url
+        FROM showcase s JOIN public.tarascope t ON t.id = s.id
+        WHERE t.batch_id = $1 ORDER BY s.ts DESC",
+    )
+    .bind(batch)
+    .fetch_all(pool)
+    .await?;
+    Ok(d)
+}
+
+/// Cancels every queued or running kaleidoscope of a batch, see [`cancel_kaleidoscope`].
+/// Returns how many were cancelled, `None` if there is no such batch.
+pub async fn cancel_batch(
+    pool: &Pool<Postgres>,
+    batch: i64,
+    keep_frames: bool,
+) -> Result<Option<u64>, DbError> {
+    let found = sqlx::query(
+        "UPDATE public.batches SET cancelled_at = COALESCE(cancelled_at, CURRENT_TIMESTAMP) WHERE id = $1",
+    )
+    .bind(batch)
+    .execute(pool)
+    .await?;
+    if found.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let ids: Vec<(String,)> = sqlx::query_as(
+        "SELECT t.id::text FROM public.tarascope t JOIN public.jobs j ON j.kaleidoid = t.id
+        WHERE t.batch_id = $1 AND j.state IN ('pending', 'running')",
+    )
+    .bind(batch)
+    .fetch_all(pool)
+    .await?;
+
+    let mut cancelled = 0;
+    for (id,) in ids {
+        if cancel_kaleidoscope(pool, &id, keep_frames).await? {
+            cancelled += 1;
+        }
+    }
+    Ok(Some(cancelled))
+}
+
+/// A file of a kaleidoscope that was put into the storage
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct StoredAsset {
+    /// video, gif, apng, thumbnail, parameters, or the path of a streaming file like `hls/master.m3u8`
+    pub name: String,
+    pub key: String,
+    pub url: String,
+    pub size: i64,
+    pub stored_at: NaiveDateTime,
+}
+
+/// Records where an asset of a kaleidoscope was stored, replaces an earlier upload
+pub async fn record_stored_asset(
+    pool: &Pool<Postgres>,
+    id: &String,
+    name: &str,
+    key: &str,
+    url: &str,
+    size: i64,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT INTO public.stored_assets (kaleidoid, name, key, url, size) VALUES (uuid($1), $2, $3, $4, $5)
+        ON CONFLICT (kaleidoid, name) DO UPDATE SET key = $3, url = $4, size = $5, stored_at = CURRENT_TIMESTAMP",
+    )
+    .bind(id)
+    .bind(name)
+    .bind(key)
+    .bind(url)
+    .bind(size)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn stored_assets(
+    pool: &Pool<Postgres>,
+    id: &String,
+) -> Result<Vec<StoredAsset>, DbError> {
+    let assets = sqlx::query_as::<_, StoredAsset>(
+        "SELECT name, key, url, size, stored_at FROM public.stored_assets WHERE kaleidoid = uuid($1) ORDER BY name",
+    )
+    .bind(id)
+    .fetch_all(pool)
+    .await?;
+    Ok(assets)
+}
+
+/// Finished kaleidoscopes that have nothing in the storage yet
+pub async fn unstored_kaleidoscopes(pool: &Pool<Postgres>) -> Result<Vec<String>, DbError> {
+    let ids: Vec<(String,)> = sqlx::query_as(
+        "SELECT t.id::text FROM public.tarascope t
+        WHERE t.status = 'done' AND NOT EXISTS (SELECT 1 FROM public.stored_assets s WHERE s.kaleidoid = t.id)
+        ORDER BY t.\"timestamp\"",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(ids.into_iter().map(|(id,)| id).collect())
+}
+
+/// A kaleidoscope a retention policy applies to
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RetentionCandidate {
+    pub id: String,
+    pub status: JobStatus,
+    /// when the kaleidoscope reached its status
+    pub since: NaiveDateTime,
+}
+
+/// Kaleidoscopes in one of `statuses` for more than `older_than_days`, oldest first.
+/// Starred ones and those rated `min_rating` or better are left out, as are kaleidoscopes
+/// whose job might still run again. With `frames_only` it's the animations that still
+/// have their frames and a thumbnail to show instead.
+pub async fn retention_candidates(
+    pool: &Pool<Postgres>,
+    statuses: &[JobStatus],
+    older_than_days: u32,
+    keep_starred: bool,
+    min_rating: Option<i16>,
+    frames_only: bool,
+) -> Result<Vec<RetentionCandidate>, DbError> {
+    let candidates = sqlx::query_as::<_, RetentionCandidate>(
+        "SELECT t.id::text, t.status, COALESCE(h.ts, t.\"timestamp\") AS since
+        FROM public.tarascope t
+        LEFT JOIN LATERAL (
+            SELECT max(ts) AS ts FROM public.status_history
+            WHERE kaleidoid = t.id AND status = t.status
+        ) h ON true
+        WHERE t.status = ANY($1)
+            AND COALESCE(h.ts, t.\"timestamp\") < CURRENT_TIMESTAMP - make_interval(days => $2)
+            AND NOT ($3 AND t.starred)
+            AND ($4::smallint IS NULL OR t.rating IS NULL OR t.rating < $4)
+            AND NOT EXISTS (
+                SELECT 1 FROM public.jobs j
+                WHERE j.kaleidoid = t.id AND j.state IN ('pending', 'running')
+            )
+            AND (NOT $5 OR (
+                t.frames_deleted_at IS NULL AND t.thumbnail IS NOT NULL
+                AND NOT EXISTS (
+                    SELECT 1 FROM public.jobs j WHERE j.kaleidoid = t.id AND j.kind = $6
+                )
+            ))
+        ORDER BY since",
+    )
+    .bind(statuses)
+    .bind(older_than_days as i32)
+    .bind(keep_starred)
+    .bind(min_rating)
+    .bind(frames_only)
+    .bind(JOB_KIND_STILL)
+    .fetch_all(pool)
+    .await?;
+    Ok(candidates)
+}
+
+/// Remembers that the frames of a kaleidoscope are gone, so retention skips it from now on
+pub async fn set_frames_deleted(pool: &Pool<Postgres>, id: &String) -> Result<(), DbError> {
+    sqlx::query("UPDATE public.tarascope SET frames_deleted_at = CURRENT_TIMESTAMP WHERE id = uuid($1)")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Status of a kaleidoscope and whether retention deleted its frames
+pub async fn kaleidoscope_frames_state(
+    pool: &Pool<Postgres>,
+    id: &String,
+) -> Result<Option<(JobStatus, bool)>, DbError> {
+    let q: Option<(JobStatus, bool)> = sqlx::query_as(
+        "SELECT status, frames_deleted_at IS NOT NULL FROM public.tarascope WHERE id = uuid($1)",
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(q)
+}
+
+/// Deletes a kaleidoscope with its frames, job, history and webhook calls
+pub async fn purge_kaleidoscope(pool: &Pool<Postgres>, id: &String) -> Result<(), DbError> {
+    sqlx::query("DELETE FROM public.tarascope WHERE id = uuid($1)")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// An entry of the retention audit log
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct RetentionLogEntry {
+    pub id: i64,
+    pub kaleidoid: String,
+    pub policy: String,
+    pub action: String,
+    pub status: JobStatus,
+    pub files: i32,
+    pub bytes: i64,
+    pub error: Option<String>,
+    pub ts: NaiveDateTime,
+}
+
+/// Adds what a policy did to a kaleidoscope to the audit log
+pub async fn record_retention(
+    pool: &Pool<Postgres>,
+    candidate: &RetentionCandidate,
+    policy: &str,
+    action: &str,
+    files: i32,
+    bytes: i64,
+    error: Option<&str>,
+) -> Result<(), DbError> {
+    sqlx::query(
+        "INSERT INTO public.retention_log (kaleidoid, policy, action, status, files, bytes, error)
+        VALUES (uuid($1), $2, $3, $4, $5, $6, $7)",
+    )
+    .bind(&candidate.id)
+    .bind(policy)
+    .bind(action)
+    .bind(candidate.status)
+    .bind(files)
+    .bind(bytes)
+    .bind(error)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The latest entries of the retention audit log, newest first
+pub async fn retention_log(
+    pool: &Pool<Postgres>,
+    limit: i64,
+) -> Result<Vec<RetentionLogEntry>, DbError> {
+    let entries = sqlx::query_as::<_, RetentionLogEntry>(
+        "SELECT id, kaleidoid::text, policy, action, status, files, bytes, error, ts
+        FROM public.retention_log ORDER BY ts DESC, id DESC LIMIT $1",
+    )
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(entries)
+}
+
+/// Stars a kaleidoscope or takes the star away, false if there is no such kaleidoscope
+pub async fn set_starred(pool: &Pool<Postgres>, id: &String, starred: bool) -> Result<bool, DbError> {
+    let res = sqlx::query("UPDATE public.tarascope SET starred = $2 WHERE id = uuid($1)")
+        .bind(id)
+        .bind(starred)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Rates a kaleidoscope from 1 to 5, `None` removes the rating.
+/// False if there is no such kaleidoscope
+pub async fn set_rating(pool: &Pool<Postgres>, id: &String, rating: Option<i16>) -> Result<bool, DbError> {
+    let res = sqlx::query("UPDATE public.tarascope SET rating = $2 WHERE id = uuid($1)")
+        .bind(id)
+        .bind(rating)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
 pub async fn get_specific_job_parameters(
     pool: &Pool<Postgres>,
     id: &String,
-) -> Result<KaleidoArgs, Box<dyn Error>> {
+) -> Result<KaleidoArgs, DbError> {
     let q: (String,) = sqlx::query_as("SELECT parameters::text FROM tarascope WHERE id = uuid($1)")
         .bind(id)
         .fetch_one(pool)
         .await?;
 
-    let vvvv: Value = serde_json::from_str(&q.0).unwrap();
-
-    println!("db: {:?}", vvvv);
+    let vvvv: Value = serde_json::from_str(&q.0)?;
 
-    Ok(KaleidoArgs::from_json(vvvv).unwrap())
+    KaleidoArgs::from_json(vvvv).map_err(|e| DbError::InvalidParameters(format!("{}: {}", id, e)))
 }
 
 pub async fn todays_done_jobs(
     pool: &Pool<Postgres>,
-) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+) -> Result<Vec<(String, String)>, DbError> {
     let q: Vec<(String, String)> = sqlx::query_as(
         "SELECT id::text, thumbnail from showcase where cast(ts as DATE) = cast(now() as DATE)",
     )
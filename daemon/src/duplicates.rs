@@ -0,0 +1,59 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres, postgres::PgExecutor};
+use tarascope::shader::KaleidoArgs;
+
+use crate::{
+    database::{SimilarKaleidoscope, similar_kaleidoscopes},
+    error::{DbError, JobError},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuplicateConfig {
+    /// Parameters this close to an existing kaleidoscope count as duplicate.
+    /// Normalized distance from 0 (identical) to 1, 0 turns the check off
+    pub epsilon: f32,
+
+    /// How often random parameters are rolled again before the job gives up
+    pub max_rerolls: u32,
+}
+
+impl Default for DuplicateConfig {
+    fn default() -> Self {
+        Self {
+            epsilon: 0.05,
+            max_rerolls: 10,
+        }
+    }
+}
+
+/// The closest existing kaleidoscope within the epsilon, if there is one
+pub async fn find_duplicate(
+    pool: impl PgExecutor<'_>,
+    kargs: &KaleidoArgs,
+    config: &DuplicateConfig,
+) -> Result<Option<SimilarKaleidoscope>, DbError> {
+    if config.epsilon <= 0.0 {
+        return Ok(None);
+    }
+    Ok(similar_kaleidoscopes(pool, kargs, config.epsilon, 1).await?.pop())
+}
+
+/// Random parameters that aren't a near duplicate of any existing kaleidoscope
+pub async fn distinct_random(
+    pool: &Pool<Postgres>,
+    config: &DuplicateConfig,
+) -> Result<KaleidoArgs, JobError> {
+    for _ in 0..=config.max_rerolls {
+        let kargs = KaleidoArgs::random();
+        match find_duplicate(pool, &kargs, config).await? {
+            None => return Ok(kargs),
+            Some(similar) => info!(
+                "rolling again, random parameters are {:.3} from {}",
+                similar.distance, similar.id
+            ),
+        }
+    }
+    Err(JobError::NoDistinctParameters(config.max_rerolls))
+}
@@ -0,0 +1,160 @@
+use std::{io, path::PathBuf};
+
+use reqwest::StatusCode;
+use sqlx::migrate::MigrateError;
+use tarascope::error::{EncodeError, RenderError};
+use thiserror::Error;
+
+/// Errors of the queries in [`crate::database`]
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("database error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+
+    #[error("couldn't apply the migrations: {0}")]
+    Migrate(#[from] MigrateError),
+
+    #[error("couldn't serialize {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// parameters that can't be turned into a kaleidoscope
+    #[error("invalid parameters: {0}")]
+    InvalidParameters(String),
+}
+
+impl DbError {
+    /// The row that was asked for doesn't exist
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, DbError::Sqlx(sqlx::Error::RowNotFound))
+    }
+
+    /// The database rejected a value of the request, e.g. an id that isn't a uuid
+    pub fn is_invalid_input(&self) -> bool {
+        match self {
+            // invalid_text_representation and check_violation
+            DbError::Sqlx(sqlx::Error::Database(e)) => matches!(e.code().as_deref(), Some("22P02" | "23514")),
+            DbError::InvalidParameters(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The database can't be reached right now, the same query may work later
+    pub fn is_unavailable(&self) -> bool {
+        matches!(
+            self,
+            DbError::Sqlx(sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_))
+        )
+    }
+}
+
+/// Errors of the asset storage in [`crate::storage`]
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("storage request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("invalid storage endpoint {0}: {1}")]
+    Endpoint(String, String),
+
+    /// the storage server refused a request
+    #[error("{action} {key} answered {status}: {body}")]
+    Rejected {
+        action: &'static str,
+        key: String,
+        status: StatusCode,
+        body: String,
+    },
+
+    /// an upload that still failed after retrying it
+    #[error("couldn't store {key}: {source}")]
+    Upload {
+        key: String,
+        source: Box<StorageError>,
+    },
+
+    #[error(transparent)]
+    Db(#[from] DbError),
+}
+
+/// Why a job failed
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error(transparent)]
+    Render(#[from] RenderError),
+
+    #[error(transparent)]
+    Encode(#[from] EncodeError),
+
+    #[error(transparent)]
+    Db(#[from] DbError),
+
+    #[error("couldn't store the assets: {0}")]
+    Storage(#[from] StorageError),
+
+    #[error("no random parameters without a near duplicate after {0} rerolls")]
+    NoDistinctParameters(u32),
+
+    #[error("invalid job kind {0}")]
+    InvalidKind(String),
+
+    #[error("job panicked: {0}")]
+    Panicked(String),
+}
+
+impl JobError {
+    /// Whether another attempt can succeed. The job goes back into the queue if it
+    /// can, otherwise it fails right away and the kaleidoscope ends up as failed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            // the parameters are read again on every attempt and won't change
+            JobError::Db(e) => !e.is_invalid_input() && !e.is_not_found(),
+            JobError::InvalidKind(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Why the configuration file couldn't be loaded
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("couldn't read {}: {source}", path.display())]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("invalid config file {}: {source}", path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+
+    /// settings that parse but can't work, see [`crate::config::DaemonConfig::validate`]
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+/// Why the daemon couldn't start
+#[derive(Debug, Error)]
+pub enum DaemonError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+
+    #[error("couldn't install the logger: {0}")]
+    Logging(String),
+
+    #[error("couldn't print the configuration: {0}")]
+    PrintConfig(#[from] toml::ser::Error),
+
+    #[error(transparent)]
+    Db(#[from] DbError),
+
+    #[error("couldn't listen for database notifications: {0}")]
+    Listener(#[from] sqlx::Error),
+
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+
+    #[error("couldn't set up the webhooks: {0}")]
+    Webhooks(#[from] reqwest::Error),
+
+    #[error("couldn't serve metrics and health checks: {0}")]
+    Http(#[from] io::Error),
+}
@@ -0,0 +1,198 @@
+use std::{
+    collections::BTreeMap,
+    fs::{remove_file, write},
+    path::PathBuf,
+    process::Stdio,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use nix::sys::statvfs::statvfs;
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tarascope::encoder::{EncodeArgs, EncoderBackend, ffmpeg_available};
+use tokio::{process::Command, sync::Mutex, time::timeout};
+
+use crate::{database::ping, queue::RenderQueue};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthConfig {
+    /// The daemon isn't ready while the output directory has less free space (in MiB)
+    pub min_free_mb: u64,
+
+    /// Seconds the results of the blender and ffmpeg checks are reused
+    pub executable_check_seconds: u64,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            min_free_mb: 1024,
+            executable_check_seconds: 60,
+        }
+    }
+}
+
+/// Result of a health check, served as json
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    /// every check with `ok` or the reason it failed
+    pub checks: BTreeMap<&'static str, String>,
+}
+
+impl HealthReport {
+    fn new(checks: Vec<(&'static str, Result<(), String>)>) -> Self {
+        let ok = checks.iter().all(|(_, res)| res.is_ok());
+        let checks = checks
+            .into_iter()
+            .map(|(name, res)| (name, res.err().unwrap_or(String::from("ok"))))
+            .collect();
+        Self { ok, checks }
+    }
+}
+
+/// Whether blender and ffmpeg could be started, from the last time it was checked
+#[derive(Debug, Clone)]
+struct ExecutableChecks {
+    checked: Instant,
+    blender: Result<(), String>,
+    ffmpeg: Result<(), String>,
+}
+
+/// Liveness and readiness of the daemon
+pub struct Health {
+    pool: Pool<Postgres>,
+    queue: Arc<RenderQueue>,
+    output_dir: PathBuf,
+    blender: String,
+    /// ffmpeg is only required if the encode settings can't do without it
+    ffmpeg_required: bool,
+    config: HealthConfig,
+    listening: AtomicBool,
+    executables: Mutex<Option<ExecutableChecks>>,
+}
+
+impl Health {
+    pub fn new(
+        pool: Pool<Postgres>,
+        queue: Arc<RenderQueue>,
+        output_dir: String,
+        blender: String,
+        encode: &EncodeArgs,
+        config: HealthConfig,
+    ) -> Self {
+        Self {
+            pool,
+            queue,
+            output_dir: PathBuf::from(output_dir),
+            blender,
+            ffmpeg_required: encode.backend == EncoderBackend::Ffmpeg || encode.hls || encode.dash,
+            config,
+            listening: AtomicBool::new(false),
+            executables: Mutex::new(None),
+        }
+    }
+
+    /// Records whether the database listener is connected
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::SeqCst);
+    }
+
+    /// The daemon is alive as long as none of its workers is wedged
+    pub fn live(&self) -> HealthReport {
+        let wedged = self.queue.wedged_workers();
+        let workers = if wedged == 0 {
+            Ok(())
+        } else {
+            Err(format!("{} workers made no progress within their lease", wedged))
+        };
+        HealthReport::new(vec![("workers", workers)])
+    }
+
+    /// The daemon is ready if it can take jobs and finish them
+    pub async fn ready(&self) -> HealthReport {
+        let database = ping(&self.pool).await.map_err(|e| e.to_string());
+
+        let listener = if self.listening.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(String::from("not listening for notifications"))
+        };
+
+        let executables = self.executables().await;
+        let ffmpeg = match executables.ffmpeg {
+            Err(e) if self.ffmpeg_required => Err(e),
+            _ => Ok(()),
+        };
+
+        HealthReport::new(vec![
+            ("database", database),
+            ("listener", listener),
+            ("blender", executables.blender),
+            ("ffmpeg", ffmpeg),
+            ("output", self.check_output()),
+        ])
+    }
+
+    /// Starting blender takes a moment, the results are cached for a while
+    async fn executables(&self) -> ExecutableChecks {
+        let mut cached = self.executables.lock().await;
+        let max_age = Duration::from_secs(self.config.executable_check_seconds);
+        if let Some(checks) = cached.as_ref()
+            && checks.checked.elapsed() < max_age
+        {
+            return checks.clone();
+        }
+
+        let blender = Command::new(&self.blender)
+            .arg("--version")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .status();
+        let blender = match timeout(Duration::from_secs(30), blender).await {
+            Ok(Ok(status)) if status.success() => Ok(()),
+            Ok(Ok(status)) => Err(format!("{} --version exited with {}", self.blender, status)),
+            Ok(Err(e)) => Err(format!("couldn't start {}: {}", self.blender, e)),
+            Err(_) => Err(format!("{} --version timed out", self.blender)),
+        };
+        let ffmpeg = if ffmpeg_available().await {
+            Ok(())
+        } else {
+            Err(String::from("ffmpeg is not executable"))
+        };
+
+        let checks = ExecutableChecks {
+            checked: Instant::now(),
+            blender,
+            ffmpeg,
+        };
+        *cached = Some(checks.clone());
+        checks
+    }
+
+    /// The output directory has to be writable and must not run full
+    fn check_output(&self) -> Result<(), String> {
+        let probe = self.output_dir.join(".readyz");
+        write(&probe, b"ok").map_err(|e| format!("{} is not writable: {}", self.output_dir.display(), e))?;
+        let _ = remove_file(&probe);
+
+        let stat = statvfs(&self.output_dir).map_err(|e| e.to_string())?;
+        let free = stat.blocks_available() as u64 * stat.fragment_size() as u64;
+        let min_free = self.config.min_free_mb * 1024 * 1024;
+        if free < min_free {
+            return Err(format!(
+                "only {} MiB free, {} MiB required",
+                free / 1024 / 1024,
+                self.config.min_free_mb
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+use std::{error::Error, io, sync::Arc};
+
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{
+    health::{Health, HealthReport},
+    metrics::metrics,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    /// Serve `/metrics`, `/healthz` and `/readyz`
+    pub enabled: bool,
+
+    /// Address and port the endpoints listen on
+    pub listen: String,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            listen: String::from("0.0.0.0:9464"),
+        }
+    }
+}
+
+/// Everything the endpoints need to answer
+struct HttpState {
+    pool: Pool<Postgres>,
+    health: Arc<Health>,
+}
+
+/// Serves the operational endpoints of the daemon on the configured address:
+/// `/metrics` for prometheus, `/healthz` (liveness) and `/readyz` (readiness)
+pub async fn serve(
+    config: &HttpConfig,
+    pool: Pool<Postgres>,
+    health: Arc<Health>,
+) -> Result<Option<JoinHandle<()>>, io::Error> {
+    if !config.enabled {
+        return Ok(None);
+    }
+
+    let listener = TcpListener::bind(&config.listen).await?;
+    info!("serving metrics and health checks on http://{}", config.listen);
+
+    let state = Arc::new(HttpState { pool, health });
+    Ok(Some(tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    debug!("http request from {}", addr);
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = respond(stream, &state).await {
+                            error!("couldn't answer http request: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("couldn't accept http connection: {}", e),
+            }
+        }
+    })))
+}
+
+/// Answers a single HTTP request
+async fn respond(mut stream: TcpStream, state: &HttpState) -> Result<(), Box<dyn Error>> {
+    let mut buf = [0; 1024];
+    let read = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..read]);
+    let path = request
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("GET "))
+        .and_then(|rest| rest.split_whitespace().next());
+
+    let (status, content_type, body) = match path {
+        Some("/metrics") => match metrics().render(&state.pool).await {
+            Ok(body) => ("200 OK", "text/plain; version=0.0.4", body),
+            Err(e) => ("500 Internal Server Error", "text/plain", e.to_string()),
+        },
+        Some("/healthz") => health_response(state.health.live()),
+        Some("/readyz") => health_response(state.health.ready().await),
+        _ => ("404 Not Found", "text/plain", String::from("not found")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn health_response(report: HealthReport) -> (&'static str, &'static str, String) {
+    let status = if report.ok {
+        "200 OK"
+    } else {
+        "503 Service Unavailable"
+    };
+    let body = serde_json::to_string(&report).unwrap_or_default();
+    (status, "application/json", body)
+}
@@ -1,92 +1,249 @@
 use std::{
-    env::current_dir,
-    error::Error,
-    sync::{Arc, OnceLock},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use clap::Parser;
-use clap_derive::Parser;
-use log::{debug, info};
+use clap_derive::{Parser, Subcommand};
+use log::{error, info, warn};
 use sqlx::{Pool, Postgres, postgres::PgListener};
-use tarascope::{
-    Tarascope,
-    shader::OutputArgs,
+use tarascope::Tarascope;
+use tokio::{
+    signal::{
+        ctrl_c,
+        unix::{SignalKind, signal},
+    },
+    time::sleep,
 };
-use tokio::sync::Mutex;
 
-use crate::{database::init_database, queue::{RenderQueue, RenderQueueRequest}};
+use crate::{
+    config::{Channel, ChannelConfig, DaemonConfig},
+    database::{CancelRequest, backfill_features, connect_database, run_migrations},
+    error::{ConfigError, DaemonError},
+    health::Health,
+    queue::{RenderQueue, RenderQueueRequest},
+    retention::Retention,
+    scheduler::Scheduler,
+    storage::AssetStore,
+    webhooks::Webhooks,
+};
 
+pub mod config;
 pub mod database;
+pub mod duplicates;
+pub mod error;
+mod health;
+mod http;
+mod logging;
+mod metrics;
 mod queue;
+pub mod retention;
+mod scheduler;
+pub mod storage;
+pub mod webhooks;
 
-pub type SharedDatabasePool = Arc<Mutex<Pool<Postgres>>>;
-pub type SharedTarascope = Arc<Mutex<Tarascope>>;
+pub type SharedTarascope = Arc<Tarascope>;
 
 /// Generate and store kaleidoscopes in postgres
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Args {
-    #[clap(flatten)]
-    out: OutputArgs,
+    #[command(subcommand)]
+    command: Option<ServerCommand>,
+
+    /// Configuration file, tarascope.toml in the working directory if it exists
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Overrides the output directory of the configuration
+    #[arg(short, long)]
+    output_dir: Option<String>,
+
+    /// Do not apply pending database migrations on startup
+    #[arg(long)]
+    skip_migrations: bool,
+}
+
+/// Config file that is used when none is given on the command line
+pub static DEFAULT_CONFIG_PATH: &str = "tarascope.toml";
+
+#[derive(Debug, Subcommand, Clone)]
+enum ServerCommand {
+    /// Apply pending database migrations and exit
+    Migrate,
 }
 
-pub async fn run() -> Result<(), Box<dyn Error>> {
+pub async fn run() -> Result<(), DaemonError> {
     // parse cli args
     let args = Args::parse();
 
     let _ = dotenv::dotenv().ok();
-    let pool = init_database().await.unwrap();
-
-    let mut listener = PgListener::connect_with(&pool).await?;
-    listener.listen("test").await?;
-    listener.listen("test2").await?;
-    listener.listen("generate_random").await?;
-    listener.listen("queue_parameters").await?;
-    listener.listen("queue_still").await?;
+
+    let mut config = match &args.config {
+        Some(path) => DaemonConfig::load(path, true)?,
+        None => DaemonConfig::load(Path::new(DEFAULT_CONFIG_PATH), false)?,
+    };
+    if let Some(dir) = args.output_dir {
+        config.output.directory = dir;
+    }
+    config.validate().map_err(ConfigError::Invalid)?;
+    logging::init(&config.logging)?;
+    info!("configuration:\n{}", toml::to_string_pretty(&config.redacted())?);
+
+    let pool = connect_database(&config.database).await?;
+
+    if let Some(ServerCommand::Migrate) = args.command {
+        return Ok(run_migrations(&pool).await?);
+    }
+
+    if !args.skip_migrations {
+        run_migrations(&pool).await?;
+    }
+    match backfill_features(&pool).await {
+        Ok(0) => {}
+        Ok(n) => info!("computed the features of {} kaleidoscopes", n),
+        Err(e) => warn!("couldn't compute missing features: {}", e),
+    }
+
+    let channels = config.channels.clone();
+    let mut listener = connect_listener(&pool, &channels).await?;
     
-    let tarascopes = Arc::new(Mutex::new(Tarascope::new(String::from(
-        args.out.output_dir,
-    ))));
+    let tarascopes = Arc::new(
+        match &config.render.blender_path {
+            Some(blender) => Tarascope::with_blender(config.output.directory.clone(), blender.clone()),
+            None => Tarascope::new(config.output.directory.clone()),
+        }
+        .with_log_limits(config.logging.jobs)
+        .with_exr_frames(config.render.exr_frames),
+    );
     
-    let r_pool = Arc::new(Mutex::new(pool));
-    let render_queue = RenderQueue::new(r_pool, tarascopes);
+    let blender = String::from(tarascopes.blender());
+    let webhooks = Webhooks::new(pool.clone(), config.webhooks.clone())?;
+    let _dispatcher = webhooks.spawn();
+    let store = AssetStore::new(&config.storage, &config.output.directory)?;
+    let render_queue = Arc::new(
+        RenderQueue::new(pool.clone(), tarascopes, webhooks, store.clone(), &config).await,
+    );
+    let _scheduler = Scheduler::spawn(pool.clone(), render_queue.clone(), config.schedule.clone());
+    let _retention = Retention::new(
+        pool.clone(),
+        config.output.directory.clone(),
+        store,
+        config.retention.clone(),
+    )
+    .spawn();
+
+    let health = Arc::new(Health::new(
+        pool.clone(),
+        render_queue.clone(),
+        config.output.directory.clone(),
+        blender,
+        &config.encode,
+        config.health.clone(),
+    ));
+    health.set_listening(true);
+    let _http = http::serve(&config.http, pool.clone(), health.clone()).await?;
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     // main event loop
     // listens for database notifications and acts upon them.
     // Spawns the tasks responsible for rendering
     loop {
         tokio::select! {
-            Ok(Some(msg)) = listener.try_recv() => {
+            msg = listener.try_recv() => {
+                let msg = match msg {
+                    Ok(Some(msg)) => msg,
+                    // the connection was lost, notifications sent in the meantime are gone
+                    Ok(None) | Err(_) => {
+                        if let Err(e) = msg {
+                            error!("database listener failed: {}", e);
+                        }
+                        warn!("database listener disconnected, reconnecting");
+                        health.set_listening(false);
+                        listener = reconnect_listener(&pool, &channels).await;
+                        health.set_listening(true);
+                        // pick up the jobs that were queued while disconnected
+                        render_queue.wake();
+                        continue;
+                    }
+                };
                 // got notification
 
                 let ch = msg.channel();
                 let data = msg.payload();
-                match ch {
-                    "test" => debug!("test notif!"),
-                    "test2" => debug!("test2 notif!"),
-
+                match channels.channel(ch) {
                     // database sent request for image generation, add to queue
-                    "generate_random" => {
-                        if let Err(e) = render_queue.push(RenderQueueRequest::RandomAnimated) {
-                            continue;
+                    Some(Channel::GenerateRandom) => {
+                        if let Err(e) = render_queue.push(RenderQueueRequest::RandomAnimated).await {
+                            error!("couldn't queue {}: {}", ch, e);
                         }
                     },
-                    "queue_parameters" =>  {
-                        if let Err(e) = render_queue.push(RenderQueueRequest::ParameterizedAnimated(String::from(data))) {
-                            continue;
+                    Some(Channel::QueueParameters) =>  {
+                        if let Err(e) = render_queue.push(RenderQueueRequest::ParameterizedAnimated(String::from(data))).await {
+                            error!("couldn't queue {}: {}", ch, e);
                         }
                     },
-                    "queue_still" =>  {
-                        if let Err(e) = render_queue.push(RenderQueueRequest::ParameterizedStill(String::from(data))) {
-                            continue;
+                    Some(Channel::QueueStill) =>  {
+                        if let Err(e) = render_queue.push(RenderQueueRequest::ParameterizedStill(String::from(data))).await {
+                            error!("couldn't queue {}: {}", ch, e);
                         }
                     },
-                    _ => {
-                        println!("unknown channel notification ({})", ch)
-                    }
+                    Some(Channel::CancelJob) => {
+                        match serde_json::from_str::<CancelRequest>(data) {
+                            Ok(request) => render_queue.cancel(request).await,
+                            Err(e) => error!("invalid cancel request {}: {}", data, e),
+                        }
+                    },
+                    None => warn!("unknown channel notification ({})", ch),
                 }
             }
+            _ = &mut shutdown => break,
         }
     }
     info!("database listener closed");
+
+    render_queue.shutdown().await;
+    Ok(())
+}
+
+/// Longest wait between two attempts to reconnect the database listener
+static MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+async fn connect_listener(
+    pool: &Pool<Postgres>,
+    channels: &ChannelConfig,
+) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen_all(channels.names()).await?;
+    Ok(listener)
+}
+
+/// Connects a new listener, retrying with an exponential backoff until the database is back
+async fn reconnect_listener(pool: &Pool<Postgres>, channels: &ChannelConfig) -> PgListener {
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match connect_listener(pool, channels).await {
+            Ok(listener) => {
+                info!("database listener reconnected");
+                return listener;
+            }
+            Err(e) => {
+                error!("couldn't reconnect the database listener, retrying in {:?}: {}", delay, e);
+                sleep(delay).await;
+                delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+            }
+        }
+    }
+}
+
+/// Resolves on SIGTERM (systemd stopping the service) or SIGINT
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("couldn't install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM, shutting down"),
+        _ = ctrl_c() => info!("received SIGINT, shutting down"),
+    }
 }
@@ -0,0 +1,64 @@
+use std::env::var;
+
+use serde::{Deserialize, Serialize};
+use tarascope::joblog::LogLimits;
+use tracing_subscriber::EnvFilter;
+
+use crate::error::DaemonError;
+
+/// Layout of the lines the daemon logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    /// human readable, the job span is printed in front of the message
+    #[default]
+    Text,
+    /// one json object per line with the job span as fields, for log collectors
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// Filter in the `RUST_LOG` syntax, e.g. `info,blender=warn`. `RUST_LOG` overrides it
+    pub filter: String,
+
+    pub format: LogFormat,
+
+    /// Caps of the blender and ffmpeg logs in the job directories
+    pub jobs: LogLimits,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            filter: String::from("info"),
+            format: LogFormat::Text,
+            jobs: LogLimits::default(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    fn env_filter(&self) -> Result<EnvFilter, String> {
+        let filter = var("RUST_LOG").unwrap_or_else(|_| self.filter.clone());
+        EnvFilter::try_new(&filter).map_err(|e| format!("invalid log filter {}: {}", filter, e))
+    }
+
+    /// Whether the filter parses, checked along with the rest of the config
+    pub fn is_valid(&self) -> bool {
+        self.env_filter().is_ok()
+    }
+}
+
+/// Installs the global subscriber. Messages of the `log` crate, which
+/// most of the daemon and tarascope still use, are forwarded to it.
+pub fn init(config: &LoggingConfig) -> Result<(), DaemonError> {
+    let filter = config.env_filter().map_err(DaemonError::Logging)?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_current_span(true).try_init(),
+    }
+    .map_err(|e| DaemonError::Logging(e.to_string()))
+}
@@ -0,0 +1,125 @@
+use std::{
+    error::Error,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use prometheus::{
+    Gauge, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use sqlx::{Pool, Postgres};
+
+use crate::database::job_counts;
+
+/// All metrics of the daemon, registered in one registry
+pub struct Metrics {
+    registry: Registry,
+    /// jobs in the jobs table by kind and state, refreshed on every scrape
+    pub jobs: IntGaugeVec,
+    /// jobs that ended on this daemon by kind and outcome
+    pub jobs_finished: IntCounterVec,
+    /// jobs currently running on this daemon
+    pub running: IntGauge,
+    pub render_duration: HistogramVec,
+    pub frame_duration: Histogram,
+    pub encode_duration: HistogramVec,
+    pub blender_exits: IntCounterVec,
+    pub queue_wait: HistogramVec,
+    /// unix time of the last frame or encode progress, stays behind when renders stall
+    pub last_progress: Gauge,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some(String::from("tarascope")), None)?;
+
+        let jobs = IntGaugeVec::new(
+            Opts::new("jobs", "Jobs in the queue by kind and state"),
+            &["kind", "state"],
+        )?;
+        let jobs_finished = IntCounterVec::new(
+            Opts::new("jobs_finished_total", "Jobs that ended on this daemon by kind and outcome"),
+            &["kind", "outcome"],
+        )?;
+        let running = IntGauge::new("running_jobs", "Jobs running on this daemon")?;
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new("render_duration_seconds", "Time blender took for a whole job")
+                .buckets(vec![10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0, 7200.0]),
+            &["kind"],
+        )?;
+        let frame_duration = Histogram::with_opts(
+            HistogramOpts::new("frame_duration_seconds", "Time between two frames reported by blender")
+                .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0]),
+        )?;
+        let encode_duration = HistogramVec::new(
+            HistogramOpts::new("encode_duration_seconds", "Time it took to stitch the frames of a job")
+                .buckets(vec![1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+            &["backend"],
+        )?;
+        let blender_exits = IntCounterVec::new(
+            Opts::new("blender_exits_total", "Blender runs by exit code"),
+            &["code"],
+        )?;
+        let queue_wait = HistogramVec::new(
+            HistogramOpts::new("queue_wait_seconds", "Time a job waited in the queue before it was claimed")
+                .buckets(vec![1.0, 10.0, 60.0, 300.0, 900.0, 1800.0, 3600.0, 7200.0, 21600.0, 86400.0]),
+            &["kind"],
+        )?;
+        let last_progress = Gauge::new(
+            "last_progress_timestamp_seconds",
+            "Unix time of the last frame or encode progress of any job",
+        )?;
+
+        registry.register(Box::new(jobs.clone()))?;
+        registry.register(Box::new(jobs_finished.clone()))?;
+        registry.register(Box::new(running.clone()))?;
+        registry.register(Box::new(render_duration.clone()))?;
+        registry.register(Box::new(frame_duration.clone()))?;
+        registry.register(Box::new(encode_duration.clone()))?;
+        registry.register(Box::new(blender_exits.clone()))?;
+        registry.register(Box::new(queue_wait.clone()))?;
+        registry.register(Box::new(last_progress.clone()))?;
+
+        Ok(Self {
+            registry,
+            jobs,
+            jobs_finished,
+            running,
+            render_duration,
+            frame_duration,
+            encode_duration,
+            blender_exits,
+            queue_wait,
+            last_progress,
+        })
+    }
+
+    /// Records that a job just made progress
+    pub fn progress(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs_f64())
+            .unwrap_or_default();
+        self.last_progress.set(now);
+    }
+
+    /// Reads the job counts from the database and renders all metrics in the text format
+    pub async fn render(&self, pool: &Pool<Postgres>) -> Result<String, Box<dyn Error>> {
+        let counts = job_counts(pool).await?;
+
+        self.jobs.reset();
+        for (kind, state, count) in counts {
+            self.jobs.with_label_values(&[kind.as_str(), state.as_str()]).set(count);
+        }
+
+        Ok(TextEncoder::new().encode_to_string(&self.registry.gather())?)
+    }
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// The metrics of this process
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("invalid metric definition"))
+}
@@ -1,22 +1,60 @@
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    env::var,
+    fs::read_to_string,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use tracing::{Instrument, Span, info_span};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use thiserror::Error;
 use tarascope::{
-    CommandType, KaleidoOutput, RenderStatus, encoder::stitch_video, shader::KaleidoArgs,
+    CommandType, KaleidoOutput, RenderJobDirectories, StatusUpdate,
+    assets::{AssetArgs, generate_assets},
+    error::RenderError,
+    encoder::{EncodeArgs, ffmpeg_available, stitch_video},
+    shader::KaleidoArgs,
 };
 use tokio::{
-    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    sync::{
+        Mutex, Notify,
+        mpsc::{UnboundedSender, unbounded_channel},
+        oneshot,
+    },
     task::JoinHandle,
+    time::{interval, timeout},
 };
 
 use crate::{
-    SharedDatabasePool, SharedTarascope,
+    SharedTarascope,
+    config::{DaemonConfig, RenderConfig},
+    duplicates::{DuplicateConfig, distinct_random},
+    error::{DbError, JobError},
+    metrics::metrics,
+    storage::AssetStore,
     database::{
-        get_specific_job_parameters, insert_frame, register_new_kaleidoscope,
-        set_kaleidoscope_to_done, set_kaleidoscope_to_waiting,
+        CancelRequest, JOB_KIND_ANIMATED, JobStatus, JOB_KIND_RANDOM, JOB_KIND_STILL, QueuedJob,
+        claim_next_job, delete_frames, enqueue_job, fail_abandoned_jobs, fail_job, finish_job,
+        get_specific_job_parameters, insert_frame, job_cancelled, job_kaleidoscope, kaleidoscope_job,
+        register_new_kaleidoscope, register_worker, release_worker_jobs,
+        renew_lease, set_encode_progress, set_job_kaleidoscope, set_kaleidoscope_assets,
+        set_kaleidoscope_status, set_kaleidoscope_to_failed, set_kaleidoscope_videos,
+        worker_heartbeat,
     },
+    webhooks::{WebhookEvent, Webhooks},
 };
 
+/// How long the queue task sleeps when there is no pending job. NOTIFY is only a
+/// wake up hint, polling makes sure a missed notification doesn't stall the queue
+static POLL_INTERVAL: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub enum RenderQueueRequest {
     RandomAnimated,
@@ -24,180 +62,911 @@ pub enum RenderQueueRequest {
     ParameterizedStill(String),
 }
 
+impl RenderQueueRequest {
+    /// Value of the `kind` column in the jobs table
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RenderQueueRequest::RandomAnimated => JOB_KIND_RANDOM,
+            RenderQueueRequest::ParameterizedAnimated(_) => JOB_KIND_ANIMATED,
+            RenderQueueRequest::ParameterizedStill(_) => JOB_KIND_STILL,
+        }
+    }
+
+    fn kaleidoscope_id(&self) -> Option<&String> {
+        match self {
+            RenderQueueRequest::RandomAnimated => None,
+            RenderQueueRequest::ParameterizedAnimated(id) => Some(id),
+            RenderQueueRequest::ParameterizedStill(id) => Some(id),
+        }
+    }
+
+    fn from_job(job: &QueuedJob) -> Option<Self> {
+        match (job.kind.as_str(), job.kaleidoid.clone()) {
+            (JOB_KIND_RANDOM, None) => Some(RenderQueueRequest::RandomAnimated),
+            // a random job that was interrupted after its parameters were generated
+            (JOB_KIND_RANDOM, Some(id)) => Some(RenderQueueRequest::ParameterizedAnimated(id)),
+            (JOB_KIND_ANIMATED, Some(id)) => Some(RenderQueueRequest::ParameterizedAnimated(id)),
+            (JOB_KIND_STILL, Some(id)) => Some(RenderQueueRequest::ParameterizedStill(id)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
 pub enum RenderQueueError {
-    QueuePushError,
+    #[error("couldn't add the job to the render queue: {0}")]
+    QueuePushError(#[from] DbError),
+}
+
+/// Result of a single job
+#[derive(Debug)]
+enum JobOutcome {
+    Done,
+    Failed(JobError),
+    Cancelled { keep_frames: bool },
+    /// stopped by a shutdown of the daemon, the job goes back into the queue
+    Interrupted,
+    /// stopped because another worker took the job over after its lease ran out
+    LeaseLost,
+}
+
+impl JobOutcome {
+    /// Label of the outcome in the metrics
+    fn label(&self) -> &'static str {
+        match self {
+            JobOutcome::Done => "done",
+            JobOutcome::Failed(_) => "failed",
+            JobOutcome::Cancelled { .. } => "cancelled",
+            JobOutcome::Interrupted => "interrupted",
+            JobOutcome::LeaseLost => "lease_lost",
+        }
+    }
+}
+
+/// Why a running job gets stopped before it is done
+#[derive(Debug)]
+enum StopReason {
+    Cancelled { keep_frames: bool },
+    Shutdown,
+    /// the lease couldn't be renewed, the job isn't ours anymore
+    LeaseLost,
+}
+
+/// Jobs running on this daemon, by job id. Sending on the channel stops the job.
+type RunningJobs = Arc<Mutex<HashMap<i64, oneshot::Sender<StopReason>>>>;
+
+/// Number of lines of blender.stderr.log that get stored with a failed job
+static STDERR_TAIL_LINES: usize = 50;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// Number of animations that get rendered at the same time
+    pub animation_workers: usize,
+
+    /// Number of stills that get rendered at the same time
+    pub still_workers: usize,
+
+    /// Name this daemon registers with in the workers table, has to be unique
+    pub worker_name: String,
+
+    /// Seconds a claimed job stays leased without progress before other workers may take it over
+    pub lease_seconds: u64,
+
+    /// Seconds between two heartbeats of this worker
+    pub heartbeat_seconds: u64,
+
+    /// How often a failing job gets attempted before it is given up
+    pub max_attempts: i32,
+
+    /// Seconds before the first retry of a failed job, doubled with every further attempt
+    pub retry_backoff_seconds: u64,
+
+    /// Seconds running jobs get to finish on shutdown before they are stopped and requeued
+    pub shutdown_grace_seconds: u64,
 }
 
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            animation_workers: 1,
+            still_workers: 1,
+            worker_name: default_worker_name(),
+            lease_seconds: 600,
+            heartbeat_seconds: 30,
+            max_attempts: 3,
+            retry_backoff_seconds: 60,
+            shutdown_grace_seconds: 300,
+        }
+    }
+}
+
+fn default_worker_name() -> String {
+    var("HOSTNAME")
+        .or_else(|_| read_to_string("/etc/hostname").map(|h| h.trim().to_string()))
+        .unwrap_or(String::from("tarascope"))
+}
+
+/// Job kinds a worker is responsible for. Still workers are reserved for stills,
+/// so a quick preview never waits for a long animation. Animation workers take
+/// stills as well, the job priority lets them jump ahead of waiting animations.
+#[derive(Debug, Clone, Copy)]
+enum WorkerClass {
+    Animation,
+    Still,
+}
+
+impl WorkerClass {
+    fn kinds(&self) -> &'static [&'static str] {
+        match self {
+            WorkerClass::Animation => &[JOB_KIND_STILL, JOB_KIND_ANIMATED, JOB_KIND_RANDOM],
+            WorkerClass::Still => &[JOB_KIND_STILL],
+        }
+    }
+}
+
+/// State shared by all workers of a queue
+#[derive(Clone)]
+struct QueueContext {
+    pool: Pool<Postgres>,
+    executor: SharedTarascope,
+    encode: Arc<EncodeArgs>,
+    assets: Arc<AssetArgs>,
+    render: Arc<RenderConfig>,
+    duplicates: Arc<DuplicateConfig>,
+    webhooks: Arc<Webhooks>,
+    store: Arc<AssetStore>,
+    wakeup: Arc<Notify>,
+    running: RunningJobs,
+    /// name of this daemon in the workers table
+    worker: Arc<str>,
+    /// lease duration in seconds
+    lease: f64,
+    /// how often phases without status messages renew the lease
+    heartbeat: Duration,
+    max_attempts: i32,
+    /// backoff before the first retry in seconds
+    backoff: f64,
+    /// set once the daemon shuts down, workers don't claim new jobs anymore
+    stopping: Arc<AtomicBool>,
+    /// unix time the worker last showed a sign of life, each worker has its own
+    beat: Arc<AtomicU64>,
+}
+
+impl QueueContext {
+    /// Copy of the context for a new worker with its own beat
+    fn for_worker(&self) -> Self {
+        let mut ctx = self.clone();
+        ctx.beat = Arc::new(AtomicU64::new(unix_time()));
+        ctx
+    }
+
+    /// Records that the worker is still making progress
+    fn beat(&self) {
+        self.beat.store(unix_time(), Ordering::SeqCst);
+    }
+
+    /// Records that the worker waits for work, a waiting worker is never wedged
+    fn idle(&self) {
+        self.beat.store(u64::MAX, Ordering::SeqCst);
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Render queue backed by the `jobs` table.
+///
+/// Jobs survive restarts of the daemon. A pool of workers claims them with
+/// `FOR UPDATE SKIP LOCKED` and gets woken up by [`RenderQueue::wake`].
 pub struct RenderQueue {
-    pool: SharedDatabasePool,
-    queue_sender: UnboundedSender<RenderQueueRequest>,
-    _handle: JoinHandle<()>,
+    pool: Pool<Postgres>,
+    wakeup: Arc<Notify>,
+    running: RunningJobs,
+    stopping: Arc<AtomicBool>,
+    worker: String,
+    grace: Duration,
+    heartbeat: JoinHandle<()>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    /// last sign of life of every worker
+    beats: Vec<Arc<AtomicU64>>,
+    /// seconds without a sign of life after which a worker counts as wedged
+    stale_after: u64,
 }
 
 impl RenderQueue {
-    pub fn new(pool: SharedDatabasePool, executor: SharedTarascope) -> Self {
-        // for the start allocate a size 2 render
-        let (queue_sender, rx) = unbounded_channel::<RenderQueueRequest>();
+    pub async fn new(
+        pool: Pool<Postgres>,
+        executor: SharedTarascope,
+        webhooks: Arc<Webhooks>,
+        store: Arc<AssetStore>,
+        config: &DaemonConfig,
+    ) -> Self {
+        let args = config.queue.clone();
+        let mut capabilities: Vec<String> = Vec::new();
+        for (class, workers) in [
+            (WorkerClass::Animation, args.animation_workers),
+            (WorkerClass::Still, args.still_workers),
+        ] {
+            if workers == 0 {
+                continue;
+            }
+            for kind in class.kinds() {
+                if !capabilities.iter().any(|c| c == kind) {
+                    capabilities.push(kind.to_string());
+                }
+            }
+        }
+        if ffmpeg_available().await {
+            capabilities.push(String::from("ffmpeg"));
+        }
 
-        Self {
+        if let Err(e) = register_worker(&pool, &args.worker_name, &capabilities, args.heartbeat_seconds).await {
+            error!("couldn't register worker {}: {}", args.worker_name, e);
+        }
+        // jobs this worker was running before it restarted are pending again
+        match release_worker_jobs(&pool, &args.worker_name).await {
+            Ok(0) => {}
+            Ok(n) => info!("requeued {} interrupted jobs", n),
+            Err(e) => error!("couldn't requeue interrupted jobs: {}", e),
+        }
+        info!("registered as worker {} ({:?})", args.worker_name, capabilities);
+
+        let wakeup = Arc::new(Notify::new());
+        let running: RunningJobs = Arc::new(Mutex::new(HashMap::new()));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let ctx = QueueContext {
             pool: pool.clone(),
-            queue_sender,
-            _handle: Self::task(pool, rx, executor),
+            executor,
+            encode: Arc::new(config.encode.clone()),
+            assets: Arc::new(config.assets.clone()),
+            render: Arc::new(config.render.clone()),
+            duplicates: Arc::new(config.duplicates.clone()),
+            webhooks,
+            store,
+            wakeup: wakeup.clone(),
+            running: running.clone(),
+            worker: Arc::from(args.worker_name.as_str()),
+            lease: args.lease_seconds as f64,
+            heartbeat: Duration::from_secs(args.heartbeat_seconds),
+            max_attempts: args.max_attempts,
+            backoff: args.retry_backoff_seconds as f64,
+            stopping: stopping.clone(),
+            beat: Arc::new(AtomicU64::new(unix_time())),
+        };
+
+        let heartbeat = Self::heartbeat(ctx.clone(), Duration::from_secs(args.heartbeat_seconds));
+        let mut handles = Vec::new();
+        let mut beats = Vec::new();
+        for (class, workers) in [
+            (WorkerClass::Animation, args.animation_workers),
+            (WorkerClass::Still, args.still_workers),
+        ] {
+            for n in 0..workers {
+                let ctx = ctx.for_worker();
+                beats.push(ctx.beat.clone());
+                handles.push(Self::worker(ctx, class, n));
+            }
+        }
+        info!(
+            "started {} animation and {} still workers",
+            args.animation_workers, args.still_workers
+        );
+
+        Self {
+            pool,
+            wakeup,
+            running,
+            stopping,
+            worker: args.worker_name,
+            grace: Duration::from_secs(args.shutdown_grace_seconds),
+            heartbeat,
+            workers: Mutex::new(handles),
+            beats,
+            stale_after: args.lease_seconds,
         }
     }
 
-    fn task(
-        pool: SharedDatabasePool,
-        mut rx: UnboundedReceiver<RenderQueueRequest>,
-        executor: SharedTarascope,
-    ) -> JoinHandle<()> {
-        // render queue task
+    /// Keeps the heartbeat of this worker in the workers table up to date
+    fn heartbeat(ctx: QueueContext, period: Duration) -> JoinHandle<()> {
         tokio::spawn(async move {
-            //let args = r_args.clone();
+            let mut interval = interval(period);
             loop {
-                if let Some(req) = rx.recv().await {
-                    //let lock = pool.lock().await;
-                    //let args = args.lock().await;
-                    match req {
-                        RenderQueueRequest::RandomAnimated => {
-                            info!("Starting new random job");
-                            let job = KaleidoArgs::random();
-
-                            let j = job.json();
-                            let id = job.get_id();
-
-                            let lock = pool.lock().await;
-                            register_new_kaleidoscope(&lock, &id, j.to_string())
-                                .await
-                                .unwrap();
-                            drop(lock);
-
-                            //render_tasks(&pool, &job).await.unwrap();
-                            let output = Self::render(
-                                pool.clone(),
-                                CommandType::Animated(1, 300, job),
-                                executor.clone(),
-                            )
-                            .await
-                            .unwrap();
-
-                        if output.exit_status.success() {
-                                let t_lock = executor.lock().await;
-                                let dirs = t_lock.paths_for_job(&id);
-                                stitch_video(&dirs).unwrap();
-                                let lock = pool.lock().await;
-                                set_kaleidoscope_to_done(&lock, &id).await.unwrap();
-                                drop(lock);
+                interval.tick().await;
+                if let Err(e) = worker_heartbeat(&ctx.pool, &ctx.worker).await {
+                    error!("heartbeat failed: {}", e);
+                }
+            }
+        })
+    }
+
+    fn worker(ctx: QueueContext, class: WorkerClass, n: usize) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            debug!("{:?} worker {} started", class, n);
+            loop {
+                // register for wake ups before looking for work, a job that is pushed
+                // between the claim and the wait would be missed otherwise
+                let notified = ctx.wakeup.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+
+                if ctx.stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                ctx.beat();
+
+                match fail_abandoned_jobs(&ctx.pool, class.kinds(), &ctx.worker, ctx.max_attempts).await {
+                    Ok(abandoned) => {
+                        for job in abandoned {
+                            warn!("gave up a {} job, its worker died on the last attempt", job.kind);
+                            if let Some(id) = job.kaleidoid {
+                                ctx.webhooks.job_finished(&id, &job.kind, WebhookEvent::Failed).await;
                             }
-                            //tokio::time::sleep(std::time::Duration::from_secs(10)).await;
-                            info!("Finished Render Job");
                         }
-                        RenderQueueRequest::ParameterizedAnimated(id) => {
-                            let lock = pool.lock().await;
-                            let job = get_specific_job_parameters(&lock, &id).await.unwrap();
-                            drop(lock);
-                            info!("Starting new parameterized job {}", id);
-                            //render_tasks(&pool, &job).await.unwrap();
-                            let output = Self::render(
-                                pool.clone(),
-                                CommandType::Animated(1, 300, job),
-                                executor.clone(),
-                            )
-                            .await
-                            .unwrap();
-
-                            if output.exit_status.success() {
-                                let t_lock = executor.lock().await;
-                                let dirs = t_lock.paths_for_job(&id);
-                                stitch_video(&dirs).unwrap();
-                                let lock = pool.lock().await;
-                                set_kaleidoscope_to_done(&lock, &id).await.unwrap();
-                                drop(lock);
+                    }
+                    Err(e) => error!("couldn't give up abandoned jobs: {}", e),
+                }
+                let job = claim_next_job(&ctx.pool, class.kinds(), &ctx.worker, ctx.lease, ctx.max_attempts).await;
+
+                match job {
+                    Ok(Some(job)) => {
+                        metrics()
+                            .queue_wait
+                            .with_label_values(&[job.kind.as_str()])
+                            .observe(job.waited);
+                        // everything logged while the job runs is tagged with it
+                        let span = info_span!(
+                            "job",
+                            id = job.id,
+                            kind = job.kind.as_str(),
+                            kaleidoscope = job.kaleidoid.as_deref()
+                        );
+                        async {
+                            let outcome = Self::run_job(&ctx, job.clone()).await;
+                            Self::record_outcome(&ctx, &job, outcome).await;
+                        }
+                        .instrument(span)
+                        .await;
+                    }
+                    Ok(None) => {
+                        ctx.idle();
+                        let _ = timeout(POLL_INTERVAL, notified).await;
+                    }
+                    Err(e) => {
+                        error!("couldn't claim next job: {}", e);
+                        let _ = timeout(POLL_INTERVAL, notified).await;
+                    }
+                }
+            }
+            debug!("{:?} worker {} stopped", class, n);
+        })
+    }
+
+    /// Runs a claimed job in its own task, so that not even a panic
+    /// inside a single job can take the worker down. The task is aborted when
+    /// the job gets cancelled, which kills blender or ffmpeg along with it.
+    async fn run_job(ctx: &QueueContext, job: QueuedJob) -> JobOutcome {
+        let Some(req) = RenderQueueRequest::from_job(&job) else {
+            return JobOutcome::Failed(JobError::InvalidKind(job.kind));
+        };
+
+        let job_id = job.id;
+        let (stop, stopped) = oneshot::channel::<StopReason>();
+        ctx.running.lock().await.insert(job_id, stop);
+        metrics().running.inc();
+
+        // a cancel that arrived between the claim and the insert above found nothing to stop
+        let cancelled = match job_cancelled(&ctx.pool, job_id).await {
+            Ok(cancelled) => cancelled,
+            Err(e) => {
+                error!("couldn't check whether job {} was cancelled: {}", job_id, e);
+                None
+            }
+        };
+
+        let outcome = match cancelled {
+            Some(keep_frames) => JobOutcome::Cancelled { keep_frames },
+            None => Self::supervise(ctx, job, req, stopped).await,
+        };
+
+        ctx.running.lock().await.remove(&job_id);
+        metrics().running.dec();
+        outcome
+    }
+
+    /// Runs the job until it finishes or gets stopped
+    async fn supervise(
+        ctx: &QueueContext,
+        job: QueuedJob,
+        req: RenderQueueRequest,
+        stopped: oneshot::Receiver<StopReason>,
+    ) -> JobOutcome {
+        let task_ctx = ctx.clone();
+        let mut handle = tokio::spawn(
+            async move { Self::process(&task_ctx, &job, req).await }.instrument(Span::current()),
+        );
+        tokio::select! {
+            res = &mut handle => match res {
+                Ok(Ok(())) => JobOutcome::Done,
+                Ok(Err(e)) => JobOutcome::Failed(e),
+                Err(e) => JobOutcome::Failed(JobError::Panicked(e.to_string())),
+            },
+            Ok(reason) = stopped => {
+                handle.abort();
+                // wait until the task is gone, so nothing writes frames anymore
+                let _ = handle.await;
+                match reason {
+                    StopReason::Cancelled { keep_frames } => JobOutcome::Cancelled { keep_frames },
+                    StopReason::Shutdown => JobOutcome::Interrupted,
+                    StopReason::LeaseLost => JobOutcome::LeaseLost,
+                }
+            }
+        }
+    }
+
+    /// Stores the outcome of a job. Failed jobs get their error and the end of
+    /// the blender log attached, and are retried with an exponential backoff.
+    /// Done jobs and jobs that gave up are reported to the webhooks.
+    async fn record_outcome(ctx: &QueueContext, job: &QueuedJob, outcome: JobOutcome) {
+        metrics()
+            .jobs_finished
+            .with_label_values(&[job.kind.as_str(), outcome.label()])
+            .inc();
+
+        let event = match outcome {
+            JobOutcome::Done => match finish_job(&ctx.pool, job.id, &ctx.worker).await {
+                Ok(true) => {
+                    // random jobs only get their kaleidoscope while running
+                    let id = job_kaleidoscope(&ctx.pool, job.id).await.ok().flatten();
+                    id.or(job.kaleidoid.clone()).map(|id| (WebhookEvent::Done, id))
+                }
+                Ok(false) => {
+                    info!("job {} was cancelled or taken over before it finished", job.id);
+                    None
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                }
+            },
+            JobOutcome::Failed(e) => {
+                error!("job {} failed: {}", job.id, e);
+                let reason = e.to_string();
+
+                // look up the kaleidoscope again, random jobs only get one while running
+                let id = match job_kaleidoscope(&ctx.pool, job.id).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!("{}", e);
+                        job.kaleidoid.clone()
+                    }
+                };
+
+                // an invalid job won't get any better by retrying it. The error of an
+                // attempt stays with the job, the kaleidoscope only fails once it gives up.
+                let max_attempts = if e.is_retryable() { ctx.max_attempts } else { 0 };
+                let res = fail_job(&ctx.pool, job.id, &ctx.worker, &reason, max_attempts, ctx.backoff).await;
+                match res {
+                    Ok(Some(true)) => {
+                        info!("job {} will be retried", job.id);
+                        if let Some(id) = &id {
+                            let res = set_kaleidoscope_status(&ctx.pool, id, JobStatus::Queued);
+                            if let Err(e) = res.await {
+                                error!("{}", e);
                             }
-                            info!("Finished Render Job");
                         }
-                        RenderQueueRequest::ParameterizedStill(id) => {
-                            let lock = pool.lock().await;
-                            let job = get_specific_job_parameters(&lock, &id).await.unwrap();
-                            drop(lock);
-                            info!("Starting new parameterized still job {}", id);
-                            let output = Self::render(
-                                pool.clone(),
-                                // only render the first frame
-                                // TODO ?
-                                CommandType::Still(0, job),
-                                executor.clone(),
-                            )
-                            .await
-                            .unwrap();
-                        
-                            if output.exit_status.success() {
-                                let lock = pool.lock().await;
-                                set_kaleidoscope_to_done(&lock, &id).await.unwrap();
-                                drop(lock);
+                        None
+                    }
+                    Ok(Some(false)) => {
+                        info!("job {} gave up", job.id);
+                        if let Some(id) = &id {
+                            let stderr = ctx
+                                .executor
+                                .paths_for_job(id)
+                                .blender_stderr_tail(STDERR_TAIL_LINES);
+                            let res = set_kaleidoscope_to_failed(&ctx.pool, id, &reason, stderr.as_deref());
+                            if let Err(e) = res.await {
+                                error!("{}", e);
                             }
-                            info!("Finished Still Render Job");
                         }
+                        id.map(|id| (WebhookEvent::Failed, id))
                     }
-                } else {
-                    info!("queue closed");
-                    break;
+                    Ok(None) => {
+                        info!("job {} was cancelled or taken over while it failed", job.id);
+                        None
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        None
+                    }
+                }
+            }
+            JobOutcome::Interrupted => {
+                // the job itself is released once all workers are stopped
+                info!("job {} interrupted", job.id);
+                let id = job_kaleidoscope(&ctx.pool, job.id).await.ok().flatten();
+                if let Some(id) = id
+                    && let Err(e) = set_kaleidoscope_status(&ctx.pool, &id, JobStatus::Queued).await
+                {
+                    error!("{}", e);
+                }
+                None
+            }
+            JobOutcome::LeaseLost => {
+                // the job and its kaleidoscope belong to the worker that took it over
+                warn!("job {} was taken over by another worker, stopped it", job.id);
+                None
+            }
+            JobOutcome::Cancelled { keep_frames } => {
+                info!("job {} cancelled", job.id);
+                if keep_frames {
+                    return;
+                }
+
+                let id = match job_kaleidoscope(&ctx.pool, job.id).await {
+                    Ok(id) => id,
+                    Err(e) => {
+                        error!("{}", e);
+                        job.kaleidoid.clone()
+                    }
+                };
+                if let Some(id) = &id {
+                    match ctx.executor.paths_for_job(id).remove_frames() {
+                        Ok(n) => info!("deleted {} partial frames of {}", n, id),
+                        Err(e) => error!("couldn't delete the frames of {}: {}", id, e),
+                    }
+                    if let Err(e) = delete_frames(&ctx.pool, id).await {
+                        error!("{}", e);
+                    }
+                }
+                None
+            }
+        };
+
+        if let Some((event, id)) = event {
+            ctx.webhooks.job_finished(&id, &job.kind, event).await;
+        }
+    }
+
+    /// Renders a single claimed job
+    async fn process(
+        ctx: &QueueContext,
+        queued: &QueuedJob,
+        req: RenderQueueRequest,
+    ) -> Result<(), JobError> {
+        let pool = &ctx.pool;
+        match req {
+            RenderQueueRequest::RandomAnimated => {
+                info!("Starting new random job");
+                let job = distinct_random(pool, &ctx.duplicates).await?;
+                let id = job.get_id();
+                Span::current().record("kaleidoscope", id.as_str());
+
+                register_new_kaleidoscope(pool, &job).await?;
+                set_job_kaleidoscope(pool, queued.id, &id).await?;
+
+                Self::render_animated(ctx, queued.id, job).await?;
+                //tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+                info!("Finished Render Job");
+                Ok(())
+            }
+            RenderQueueRequest::ParameterizedAnimated(id) => {
+                let job = get_specific_job_parameters(pool, &id).await?;
+                info!("Starting new parameterized job {}", id);
+
+                Self::render_animated(ctx, queued.id, job).await?;
+                info!("Finished Render Job");
+                Ok(())
+            }
+            RenderQueueRequest::ParameterizedStill(id) => {
+                let job = get_specific_job_parameters(pool, &id).await?;
+                info!("Starting new parameterized still job {}", id);
+                let output = Self::render(
+                    ctx,
+                    CommandType::Still(ctx.render.still_frame, job),
+                    Self::status_collector(ctx, queued.id),
+                )
+                .await?;
+
+                if !output.exit_status.success() {
+                    return Err(RenderError::Exit(output.exit_status).into());
                 }
+
+                let dirs = ctx.executor.paths_for_job(&id);
+                Self::keep_alive(ctx, queued.id, async {
+                    Self::create_assets(pool, &dirs, &ctx.assets).await;
+                    Self::store_assets(ctx, &dirs).await
+                })
+                .await?;
+                info!("Finished Still Render Job");
+                Ok(())
             }
+        }
+    }
+
+    /// Renders, stitches and finishes an animated kaleidoscope
+    async fn render_animated(
+        ctx: &QueueContext,
+        job_id: i64,
+        job: KaleidoArgs,
+    ) -> Result<(), JobError> {
+        let pool = &ctx.pool;
+        let id = job.get_id();
+        let status = Self::status_collector(ctx, job_id);
+        let frames = CommandType::Animated(ctx.render.frame_start, ctx.render.frame_end, job);
+        let output = Self::render(ctx, frames, status.clone()).await?;
+
+        if !output.exit_status.success() {
+            return Err(RenderError::Exit(output.exit_status).into());
+        }
+
+        set_kaleidoscope_status(pool, &id, JobStatus::Encoding).await?;
+
+        let dirs = ctx.executor.paths_for_job(&id);
+        let started = Instant::now();
+        // the native encoder only reports progress between the formats
+        let videos = Self::keep_alive(ctx, job_id, stitch_video(&dirs, &ctx.encode, status)).await?;
+        set_kaleidoscope_videos(pool, &id, &videos).await?;
+        let backend = format!("{:?}", ctx.encode.backend).to_lowercase();
+        metrics()
+            .encode_duration
+            .with_label_values(&[backend.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+        Self::keep_alive(ctx, job_id, async {
+            Self::create_assets(pool, &dirs, &ctx.assets).await;
+            Self::store_assets(ctx, &dirs).await
         })
+        .await?;
+        Ok(())
     }
 
-    async fn render(
-        pool: SharedDatabasePool,
-        job: CommandType,
-        executor: SharedTarascope,
-    ) -> Result<KaleidoOutput, Box<dyn Error>> {
-        let id = job.get_job_id();
-        let (sender, receiver) = unbounded_channel::<String>();
+    /// Runs a phase of a job that sends few or no status messages, like encoding,
+    /// generating and storing the assets. The beat of the worker and the lease of the job are
+    /// renewed meanwhile, a slow upload is no sign of a wedged worker.
+    async fn keep_alive<T>(ctx: &QueueContext, job_id: i64, phase: impl Future<Output = T>) -> T {
+        tokio::pin!(phase);
+        let mut ticks = interval(ctx.heartbeat);
+        loop {
+            tokio::select! {
+                output = &mut phase => return output,
+                _ = ticks.tick() => {
+                    ctx.beat();
+                    if !Self::renew_lease(ctx, job_id).await {
+                        // the job is being stopped, its task gets aborted
+                        return phase.await;
+                    }
+                }
+            }
+        }
+    }
 
-        // status collector task
-        let pool_task = pool.clone();
-        tokio::spawn(async move {
-            let mut receiver = receiver;
-            loop {
-                if let Some(msg) = receiver.recv().await {
+    /// Renews the lease of a running job. A job whose lease can't be renewed belongs
+    /// to another worker by now, or was cancelled, and gets stopped so that two
+    /// renders never write the same frames. Returns false once the job was stopped.
+    async fn renew_lease(ctx: &QueueContext, job_id: i64) -> bool {
+        match renew_lease(&ctx.pool, job_id, &ctx.worker, ctx.lease).await {
+            Ok(true) => true,
+            Ok(false) => {
+                // the cancel notification may not have arrived yet
+                let reason = match job_cancelled(&ctx.pool, job_id).await {
+                    Ok(Some(keep_frames)) => StopReason::Cancelled { keep_frames },
+                    _ => StopReason::LeaseLost,
+                };
+                if let Some(stop) = ctx.running.lock().await.remove(&job_id) {
+                    warn!("job {} isn't ours anymore, stopping it ({:?})", job_id, reason);
+                    let _ = stop.send(reason);
+                }
+                false
+            }
+            Err(e) => {
+                error!("couldn't renew lease of job {}: {}", job_id, e);
+                true
+            }
+        }
+    }
+
+    /// Puts the finished assets into the configured storage
+    async fn store_assets(ctx: &QueueContext, dirs: &RenderJobDirectories) -> Result<(), JobError> {
+        set_kaleidoscope_status(&ctx.pool, &dirs.get_id(), JobStatus::Uploading).await?;
+
+        ctx.store.store(&ctx.pool, dirs).await?;
+        Ok(())
+    }
+
+    /// Generates thumbnail, poster and contact sheet of a finished job and records them.
+    /// A failure here only gets logged, the job itself is still usable without them.
+    async fn create_assets(pool: &Pool<Postgres>, dirs: &RenderJobDirectories, args: &AssetArgs) {
+        let id = dirs.get_id();
+        match generate_assets(dirs, args).await {
+            Ok(assets) => {
+                if let Err(e) = set_kaleidoscope_assets(pool, &id, &assets).await {
+                    error!("couldn't record assets of {}: {}", id, e);
+                }
+            }
+            Err(e) => error!("couldn't generate assets of {}: {}", id, e),
+        }
+    }
+
+    /// Spawns the task that stores the status messages of a job (rendered frames,
+    /// encode progress) in the database. Every message proves that the job is still
+    /// making progress and renews its lease. The task ends once every sender is
+    /// dropped, or once the job was stopped because its lease was lost.
+    fn status_collector(ctx: &QueueContext, job_id: i64) -> UnboundedSender<String> {
+        let (sender, mut receiver) = unbounded_channel::<String>();
+        let ctx = ctx.clone();
+
+        tokio::spawn(
+            async move {
+                let mut last_frame: Option<Instant> = None;
+                while let Some(msg) = receiver.recv().await {
                     debug!("{:?}", msg);
+                    metrics().progress();
+                    ctx.beat();
 
-                    //let p = self.pool.clone();
-                    let pool = pool_task.lock().await;
+                    let data: StatusUpdate = match serde_json::from_str(msg.as_str()) {
+                        Ok(data) => data,
+                        Err(e) => {
+                            error!("invalid status message {}: {}", msg, e);
+                            continue;
+                        }
+                    };
 
-                    let data: RenderStatus = serde_json::from_str(msg.as_str()).unwrap();
-                    insert_frame(&pool, data).await.unwrap();
-                    continue;
-                } else {
-                    break;
+                    if !Self::renew_lease(&ctx, job_id).await {
+                        break;
+                    }
+
+                    if let StatusUpdate::Frame(_) = data {
+                        if let Some(last) = last_frame {
+                            metrics().frame_duration.observe(last.elapsed().as_secs_f64());
+                        }
+                        last_frame = Some(Instant::now());
+                    }
+
+                    let res = match data {
+                        StatusUpdate::Frame(status) => insert_frame(&ctx.pool, status).await,
+                        StatusUpdate::Encode(status) => set_encode_progress(&ctx.pool, status).await,
+                    };
+                    if let Err(e) = res {
+                        error!("{}", e);
+                    }
                 }
             }
-        });
+            .instrument(Span::current()),
+        );
 
-        let pool = pool.clone();
+        sender
+    }
 
-        let lock = pool.lock().await;
-        if let Err(e) = set_kaleidoscope_to_waiting(&lock, &id).await {
+    async fn render(
+        ctx: &QueueContext,
+        job: CommandType,
+        sender: UnboundedSender<String>,
+    ) -> Result<KaleidoOutput, RenderError> {
+        let id = job.get_job_id();
+        let kind = match &job {
+            CommandType::Animated(..) => "animated",
+            CommandType::Still(..) => "still",
+        };
+
+        if let Err(e) = set_kaleidoscope_status(&ctx.pool, &id, JobStatus::Rendering).await {
             error!("{}", e);
         }
-        drop(lock);
+        // a still render adds a frame to an existing kaleidoscope, its archives miss that frame
+        match ctx.executor.paths_for_job(&id).remove_archives() {
+            Ok(0) => {}
+            Ok(n) => debug!("removed {} outdated archives of {}", n, id),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("couldn't remove the archives of {}: {}", id, e),
+        }
+
+        let started = Instant::now();
+        let output = ctx.executor.start_render(job, sender).await?;
 
-        let t_lock = executor.lock().await;
-        let output = t_lock.start_render(job, sender).await?;
+        let code = match output.exit_status.code() {
+            Some(code) => code.to_string(),
+            None => String::from("signal"),
+        };
+        metrics().blender_exits.with_label_values(&[code.as_str()]).inc();
+        metrics()
+            .render_duration
+            .with_label_values(&[kind])
+            .observe(started.elapsed().as_secs_f64());
 
         Ok(output)
     }
 
-    pub fn push(&self, request: RenderQueueRequest) -> Result<(), RenderQueueError> {
-        //debug!("queue capacity: {}", self.queue_sender.capacity());
+    /// Adds a request to the jobs table and wakes the workers.
+    /// Requests for a kaleidoscope that already has a job are ignored.
+    pub async fn push(&self, request: RenderQueueRequest) -> Result<(), RenderQueueError> {
         debug!("Adding {:?} to the queue", request);
-        if let Err(e) = self.queue_sender.send(request) {
-            error!("error while adding task to render queue: {}", e);
-            Err(RenderQueueError::QueuePushError)
-            //Err(e)
-        } else {
-            Ok(())
+        enqueue_job(&self.pool, request.kind(), request.kaleidoscope_id()).await?;
+
+        self.wake();
+        Ok(())
+    }
+
+    /// Stops a job of this daemon that was cancelled. The database side of the
+    /// cancellation already happened, jobs running on other daemons are ignored.
+    pub async fn cancel(&self, request: CancelRequest) {
+        let job = match kaleidoscope_job(&self.pool, &request.id).await {
+            Ok(Some(job)) => job,
+            Ok(None) => return,
+            Err(e) => {
+                error!("couldn't look up the job of {}: {}", request.id, e);
+                return;
+            }
+        };
+
+        if let Some(cancel) = self.running.lock().await.remove(&job) {
+            info!("cancelling job {} ({})", job, request.id);
+            let _ = cancel.send(StopReason::Cancelled {
+                keep_frames: request.keep_frames,
+            });
+        }
+    }
+
+    /// Stops the queue: no new jobs get claimed, running jobs get the grace period
+    /// to finish. Jobs still running after that are stopped and put back into the
+    /// queue, their frames stay on disk for the next attempt.
+    pub async fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        self.wake();
+
+        let mut workers: Vec<JoinHandle<()>> = self.workers.lock().await.drain(..).collect();
+        let running = self.running.lock().await.len();
+        if running > 0 {
+            info!("waiting up to {:?} for {} running jobs", self.grace, running);
         }
+
+        let all_stopped = async {
+            for worker in workers.iter_mut() {
+                let _ = worker.await;
+            }
+        };
+        if timeout(self.grace, all_stopped).await.is_err() {
+            let mut running = self.running.lock().await;
+            info!("grace period is over, stopping {} jobs", running.len());
+            for (_, stop) in running.drain() {
+                let _ = stop.send(StopReason::Shutdown);
+            }
+            drop(running);
+
+            // a handle must not be awaited again once it completed
+            workers.retain(|worker| !worker.is_finished());
+            for worker in workers {
+                let _ = worker.await;
+            }
+        }
+        self.heartbeat.abort();
+
+        match release_worker_jobs(&self.pool, &self.worker).await {
+            Ok(0) => {}
+            Ok(n) => info!("requeued {} interrupted jobs", n),
+            Err(e) => error!("couldn't requeue interrupted jobs: {}", e),
+        }
+        info!("render queue stopped");
+    }
+
+    /// Number of workers that showed no sign of life for longer than a lease.
+    /// Idle workers poll regularly and running jobs report every frame,
+    /// so a silent worker is stuck. Workers that stopped on shutdown don't count.
+    pub fn wedged_workers(&self) -> usize {
+        if self.stopping.load(Ordering::SeqCst) {
+            return 0;
+        }
+        let now = unix_time();
+        self.beats
+            .iter()
+            .filter(|beat| now.saturating_sub(beat.load(Ordering::SeqCst)) > self.stale_after)
+            .count()
+    }
+
+    /// Makes all idle workers look for pending jobs
+    pub fn wake(&self) {
+        self.wakeup.notify_waiters();
     }
 }
@@ -0,0 +1,423 @@
+use std::{
+    fs::{read_dir, remove_dir_all},
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tarascope::RenderJobDirectories;
+use tokio::{task::JoinHandle, time::interval};
+
+use crate::{
+    database::{
+        JobStatus, RetentionCandidate, purge_kaleidoscope, record_retention, retention_candidates,
+        set_frames_deleted,
+    },
+    storage::AssetStore,
+};
+
+/// What a retention policy deletes
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetentionAction {
+    /// the rendered frames of animations, videos, thumbnail, poster and logs stay
+    DeleteFrames,
+    /// the whole job directory and the kaleidoscope in the database
+    Purge,
+}
+
+impl RetentionAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetentionAction::DeleteFrames => "delete-frames",
+            RetentionAction::Purge => "purge",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Name the policy is reported and logged with
+    pub name: String,
+
+    pub action: RetentionAction,
+
+    /// Statuses the policy applies to, only done, failed and cancelled are allowed
+    pub statuses: Vec<JobStatus>,
+
+    /// Days a kaleidoscope has to be in its status before the policy applies
+    pub older_than_days: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Policies the daemon applies, in order
+    pub policies: Vec<RetentionPolicy>,
+
+    /// Seconds between two runs of the policies
+    pub interval_seconds: u64,
+
+    /// Only log what the policies would delete
+    pub dry_run: bool,
+
+    /// Starred kaleidoscopes are never touched
+    pub keep_starred: bool,
+
+    /// Kaleidoscopes rated this or better are never touched
+    pub keep_min_rating: Option<i16>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            policies: Vec::new(),
+            interval_seconds: 3600,
+            dry_run: false,
+            keep_starred: true,
+            keep_min_rating: Some(4),
+        }
+    }
+}
+
+/// What a policy deleted from a kaleidoscope, or would delete in a dry run
+#[derive(Debug, Serialize)]
+pub struct RetentionItem {
+    pub kaleidoscope: RetentionCandidate,
+    pub files: u64,
+    pub bytes: u64,
+    pub error: Option<String>,
+}
+
+/// Result of a single policy
+#[derive(Debug, Serialize)]
+pub struct PolicyReport {
+    pub policy: String,
+    pub action: RetentionAction,
+    pub dry_run: bool,
+    pub items: Vec<RetentionItem>,
+}
+
+impl PolicyReport {
+    pub fn files(&self) -> u64 {
+        self.items.iter().filter(|i| i.error.is_none()).map(|i| i.files).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.items.iter().filter(|i| i.error.is_none()).map(|i| i.bytes).sum()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.items.iter().filter(|i| i.error.is_some()).count()
+    }
+}
+
+/// Human readable size of `bytes`
+pub fn format_bytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / 1024.0 / 1024.0)
+}
+
+/// Frees disk space by applying the retention policies to the job directories.
+/// Everything that gets deleted is recorded in the `retention_log` table.
+pub struct Retention {
+    pool: Pool<Postgres>,
+    output_dir: String,
+    /// purged kaleidoscopes lose their stored assets as well
+    store: Arc<AssetStore>,
+    config: RetentionConfig,
+}
+
+impl Retention {
+    pub fn new(
+        pool: Pool<Postgres>,
+        output_dir: String,
+        store: Arc<AssetStore>,
+        config: RetentionConfig,
+    ) -> Self {
+        Self {
+            pool,
+            output_dir,
+            store,
+            config,
+        }
+    }
+
+    /// Applies the policies periodically, unless there are none
+    pub fn spawn(self) -> Option<JoinHandle<()>> {
+        if self.config.policies.is_empty() {
+            return None;
+        }
+        info!(
+            "applying {} retention policies every {} seconds{}",
+            self.config.policies.len(),
+            self.config.interval_seconds,
+            if self.config.dry_run { " (dry run)" } else { "" }
+        );
+
+        Some(tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(self.config.interval_seconds));
+            loop {
+                interval.tick().await;
+                let reports = self.run(self.config.dry_run).await;
+                for report in reports.iter().filter(|r| !r.items.is_empty()) {
+                    info!(
+                        "retention {}: {} {} files ({}) of {} kaleidoscopes, {} errors",
+                        report.policy,
+                        if report.dry_run { "would delete" } else { "deleted" },
+                        report.files(),
+                        format_bytes(report.bytes()),
+                        report.items.len(),
+                        report.errors()
+                    );
+                }
+            }
+        }))
+    }
+
+    /// Applies every policy in order. With `dry_run` nothing gets deleted,
+    /// the reports list what would have been.
+    pub async fn run(&self, dry_run: bool) -> Vec<PolicyReport> {
+        let mut reports = Vec::new();
+        for policy in &self.config.policies {
+            match self.apply(policy, dry_run).await {
+                Ok(report) => reports.push(report),
+                Err(e) => error!("couldn't apply retention policy {}: {}", policy.name, e),
+            }
+        }
+        reports
+    }
+
+    async fn apply(&self, policy: &RetentionPolicy, dry_run: bool) -> Result<PolicyReport, String> {
+        let candidates = retention_candidates(
+            &self.pool,
+            &policy.statuses,
+            policy.older_than_days,
+            self.config.keep_starred,
+            self.config.keep_min_rating,
+            policy.action == RetentionAction::DeleteFrames,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+        let mut items = Vec::new();
+        for candidate in candidates {
+            let dirs = RenderJobDirectories::new(candidate.id.clone(), self.output_dir.clone());
+            let mut item = Self::survey(&dirs, candidate, policy.action);
+            if !dry_run && item.error.is_none() {
+                item.error = self.delete(&dirs, &item, policy.action).await.err();
+                self.record(policy, &item).await;
+            }
+            items.push(item);
+        }
+
+        Ok(PolicyReport {
+            policy: policy.name.clone(),
+            action: policy.action,
+            dry_run,
+            items,
+        })
+    }
+
+    /// Counts what the action would delete from a kaleidoscope, without touching anything
+    fn survey(
+        dirs: &RenderJobDirectories,
+        candidate: RetentionCandidate,
+        action: RetentionAction,
+    ) -> RetentionItem {
+        match Self::files(dirs, action) {
+            Ok(files) => RetentionItem {
+                kaleidoscope: candidate,
+                files: files.len() as u64,
+                bytes: files.iter().filter_map(|f| f.metadata().ok()).map(|m| m.len()).sum(),
+                error: None,
+            },
+            Err(e) => RetentionItem {
+                kaleidoscope: candidate,
+                files: 0,
+                bytes: 0,
+                error: Some(format!("couldn't list the files: {}", e)),
+            },
+        }
+    }
+
+    /// Files the action would delete, none if the job directory is already gone
+    fn files(dirs: &RenderJobDirectories, action: RetentionAction) -> io::Result<Vec<PathBuf>> {
+        let files = match action {
+            RetentionAction::DeleteFrames => dirs.frame_files().and_then(|mut frames| {
+                frames.extend(dirs.frame_files_with_extension("exr")?);
+                Ok(frames)
+            }),
+            RetentionAction::Purge => files_below(Path::new(&dirs.project_folder_path())),
+        };
+        match files {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            files => files,
+        }
+    }
+
+    /// Deletes the files first, the database only forgets about them once they are gone
+    async fn delete(
+        &self,
+        dirs: &RenderJobDirectories,
+        item: &RetentionItem,
+        action: RetentionAction,
+    ) -> Result<(), String> {
+        let id = &item.kaleidoscope.id;
+        if action == RetentionAction::Purge {
+            self.store.remove(&self.pool, id).await.map_err(|e| e.to_string())?;
+        }
+        let removed = match action {
+            RetentionAction::DeleteFrames => dirs.remove_frames().map(|_| ()),
+            RetentionAction::Purge => remove_dir_all(dirs.project_folder_path()),
+        };
+        if let Err(e) = removed
+            && e.kind() != ErrorKind::NotFound
+        {
+            return Err(e.to_string());
+        }
+
+        match action {
+            RetentionAction::DeleteFrames => set_frames_deleted(&self.pool, id).await,
+            RetentionAction::Purge => purge_kaleidoscope(&self.pool, id).await,
+        }
+        .map_err(|e| e.to_string())
+    }
+
+    /// Adds the item to the audit log
+    async fn record(&self, policy: &RetentionPolicy, item: &RetentionItem) {
+        if let Some(e) = &item.error {
+            warn!(
+                "retention {} couldn't {} {}: {}",
+                policy.name,
+                policy.action.as_str(),
+                item.kaleidoscope.id,
+                e
+            );
+        }
+
+        let res = record_retention(
+            &self.pool,
+            &item.kaleidoscope,
+            &policy.name,
+            policy.action.as_str(),
+            item.files as i32,
+            item.bytes as i64,
+            item.error.as_deref(),
+        )
+        .await
+        .map_err(|e| e.to_string());
+
+        if let Err(e) = res {
+            error!("couldn't record retention of {}: {}", item.kaleidoscope.id, e);
+        }
+    }
+}
+
+/// Every file in `dir` and its subdirectories
+fn files_below(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            files.extend(files_below(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+
+    use super::*;
+
+    /// A finished job with two frames, an exr frame, a video and a log in a subdirectory
+    fn job(name: &str) -> RenderJobDirectories {
+        let output = std::env::temp_dir().join(format!("tarascope-retention-{}-{}", name, std::process::id()));
+        let dirs = RenderJobDirectories::new(String::from("job"), output.to_string_lossy().to_string());
+        std::fs::create_dir_all(dirs.hls_folder_path()).unwrap();
+        std::fs::write(format!("{}/frame_00001.png", dirs.project_folder_path()), b"12345").unwrap();
+        std::fs::write(format!("{}/frame_00002.png", dirs.project_folder_path()), b"12345").unwrap();
+        std::fs::write(format!("{}/frame_00001.exr", dirs.project_folder_path()), b"1234567890").unwrap();
+        std::fs::write(dirs.gif_path(), b"gif").unwrap();
+        std::fs::write(dirs.hls_playlist_path(), b"#EXTM3U").unwrap();
+        dirs
+    }
+
+    fn candidate() -> RetentionCandidate {
+        RetentionCandidate {
+            id: String::from("job"),
+            status: JobStatus::Done,
+            since: NaiveDateTime::default(),
+        }
+    }
+
+    fn names(files: &[PathBuf]) -> Vec<String> {
+        let mut names: Vec<String> =
+            files.iter().map(|f| f.file_name().unwrap().to_string_lossy().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn actions_select_their_files() {
+        let dirs = job("files");
+        let frames = Retention::files(&dirs, RetentionAction::DeleteFrames).unwrap();
+        let purged = Retention::files(&dirs, RetentionAction::Purge).unwrap();
+        std::fs::remove_dir_all(dirs.output_dir()).unwrap();
+
+        assert_eq!(names(&frames), ["frame_00001.exr", "frame_00001.png", "frame_00002.png"]);
+        assert_eq!(
+            names(&purged),
+            ["frame_00001.exr", "frame_00001.png", "frame_00002.png", "master.m3u8", "video.gif"]
+        );
+    }
+
+    #[test]
+    fn missing_directories_have_nothing_to_delete() {
+        let dirs = RenderJobDirectories::new(String::from("gone"), String::from("/nonexistent"));
+        for action in [RetentionAction::DeleteFrames, RetentionAction::Purge] {
+            assert!(Retention::files(&dirs, action).unwrap().is_empty());
+            let item = Retention::survey(&dirs, candidate(), action);
+            assert_eq!((item.files, item.bytes, item.error), (0, 0, None));
+        }
+    }
+
+    #[test]
+    fn dry_run_reports_without_deleting() {
+        let dirs = job("survey");
+        let frames = Retention::survey(&dirs, candidate(), RetentionAction::DeleteFrames);
+        let purge = Retention::survey(&dirs, candidate(), RetentionAction::Purge);
+        let left = Retention::files(&dirs, RetentionAction::Purge).unwrap().len();
+        std::fs::remove_dir_all(dirs.output_dir()).unwrap();
+
+        assert_eq!((frames.files, frames.bytes), (3, 20));
+        assert_eq!((purge.files, purge.bytes), (5, 30));
+        assert_eq!(left, 5);
+    }
+
+    #[test]
+    fn totals_leave_out_failed_items() {
+        let item = |files, bytes, error: Option<&str>| RetentionItem {
+            kaleidoscope: candidate(),
+            files,
+            bytes,
+            error: error.map(String::from),
+        };
+        let report = PolicyReport {
+            policy: String::from("old-frames"),
+            action: RetentionAction::DeleteFrames,
+            dry_run: true,
+            items: vec![item(3, 3 * 1024 * 1024, None), item(2, 100, Some("denied")), item(1, 512 * 1024, None)],
+        };
+
+        assert_eq!((report.files(), report.bytes(), report.errors()), (4, 3 * 1024 * 1024 + 512 * 1024, 1));
+        assert_eq!(format_bytes(report.bytes()), "3.5 MiB");
+    }
+}
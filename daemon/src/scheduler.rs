@@ -0,0 +1,362 @@
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{Local, NaiveTime, Utc};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Postgres};
+use tokio::{task::JoinHandle, time::interval};
+
+use crate::{
+    database::{SchedulerCounts, schedule_random_jobs},
+    queue::RenderQueue,
+};
+
+/// What a schedule rule asks for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleTarget {
+    /// keep this many random animations queued
+    Keep(i64),
+    /// render this many random animations per day, one after another
+    Daily(i64),
+}
+
+/// A rule of the scheduler, written as `keep:N` or `daily:N` with an optional
+/// time window appended, e.g. `keep:3@01:00-06:00`. Windows may wrap around midnight.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ScheduleRule {
+    pub target: ScheduleTarget,
+    pub window: Option<(NaiveTime, NaiveTime)>,
+}
+
+impl FromStr for ScheduleRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rule, window) = match s.split_once('@') {
+            Some((rule, window)) => (rule, Some(window)),
+            None => (s, None),
+        };
+
+        let (kind, count) = rule
+            .split_once(':')
+            .ok_or(format!("expected keep:N or daily:N, got {}", rule))?;
+        let count: i64 = count
+            .parse()
+            .map_err(|e| format!("invalid count {}: {}", count, e))?;
+        if count < 1 {
+            return Err(format!("the count of {} has to be at least 1, got {}", rule, count));
+        }
+        let target = match kind {
+            "keep" => ScheduleTarget::Keep(count),
+            "daily" => ScheduleTarget::Daily(count),
+            _ => return Err(format!("unknown rule {}, expected keep or daily", kind)),
+        };
+
+        let window = match window {
+            Some(window) => {
+                let (start, end) = window
+                    .split_once('-')
+                    .ok_or(format!("expected HH:MM-HH:MM, got {}", window))?;
+                let parse = |t: &str| {
+                    NaiveTime::parse_from_str(t, "%H:%M").map_err(|e| format!("invalid time {}: {}", t, e))
+                };
+                Some((parse(start)?, parse(end)?))
+            }
+            None => None,
+        };
+
+        Ok(Self { target, window })
+    }
+}
+
+impl TryFrom<String> for ScheduleRule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ScheduleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.target {
+            ScheduleTarget::Keep(n) => write!(f, "keep:{}", n)?,
+            ScheduleTarget::Daily(n) => write!(f, "daily:{}", n)?,
+        }
+        if let Some((start, end)) = self.window {
+            write!(f, "@{}-{}", start.format("%H:%M"), end.format("%H:%M"))?;
+        }
+        Ok(())
+    }
+}
+
+impl From<ScheduleRule> for String {
+    fn from(rule: ScheduleRule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl ScheduleRule {
+    fn active(&self, now: NaiveTime) -> bool {
+        match self.window {
+            None => true,
+            Some((start, end)) if start <= end => start <= now && now < end,
+            // the window spans midnight
+            Some((start, end)) => now >= start || now < end,
+        }
+    }
+
+    /// How many random animations this rule wants to add to the queue
+    fn wanted(&self, counts: &SchedulerCounts) -> i64 {
+        match self.target {
+            ScheduleTarget::Keep(n) => n - counts.random_queued,
+            ScheduleTarget::Daily(n) if counts.random_queued == 0 && counts.random_today < n => 1,
+            ScheduleTarget::Daily(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// Rules for generating random animations without anyone asking for them.
+    /// `keep:N` keeps N random animations queued, `daily:N` renders N per day.
+    /// Append `@HH:MM-HH:MM` to apply a rule only during that time, e.g. `keep:3@01:00-06:00`
+    pub rules: Vec<ScheduleRule>,
+
+    /// Maximum number of random animations that get created per day
+    pub daily_quota: Option<i64>,
+
+    /// Seconds between two runs of the scheduler
+    pub interval_seconds: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            daily_quota: None,
+            interval_seconds: 60,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    /// How many random animations to queue at `now`. Nothing gets queued while
+    /// user jobs are waiting, otherwise the most demanding active rule wins.
+    fn wanted(&self, counts: &SchedulerCounts, now: NaiveTime) -> i64 {
+        if counts.user_pending > 0 {
+            return 0;
+        }
+
+        let mut wanted = self
+            .rules
+            .iter()
+            .filter(|rule| rule.active(now))
+            .map(|rule| rule.wanted(counts))
+            .max()
+            .unwrap_or(0);
+        if let Some(quota) = self.daily_quota {
+            wanted = wanted.min(quota - counts.random_today);
+        }
+        wanted.max(0)
+    }
+}
+
+/// Queues random animations according to the configured rules.
+/// Nothing gets queued while user jobs are waiting.
+pub struct Scheduler {
+    pool: Pool<Postgres>,
+    queue: Arc<RenderQueue>,
+    config: SchedulerConfig,
+}
+
+impl Scheduler {
+    /// Starts the scheduler, unless there are no rules
+    pub fn spawn(
+        pool: Pool<Postgres>,
+        queue: Arc<RenderQueue>,
+        config: SchedulerConfig,
+    ) -> Option<JoinHandle<()>> {
+        if config.rules.is_empty() {
+            return None;
+        }
+        info!("scheduling random animations: {:?}", config.rules);
+
+        let scheduler = Self { pool, queue, config };
+        Some(tokio::spawn(async move {
+            let mut interval = interval(Duration::from_secs(scheduler.config.interval_seconds));
+            loop {
+                interval.tick().await;
+                scheduler.tick().await;
+            }
+        }))
+    }
+
+    /// Every daemon runs a scheduler, the ticks are serialized through a lock in the
+    /// database so that several daemons don't add up to more jobs than the rules ask for
+    async fn tick(&self) {
+        // the windows and the daily counts both follow the local clock of the daemon
+        let now = Local::now();
+        let today = now
+            .date_naive()
+            .and_time(NaiveTime::MIN)
+            .and_local_timezone(Local)
+            .earliest()
+            .unwrap_or(now)
+            .with_timezone(&Utc);
+        let res = schedule_random_jobs(&self.pool, today, |counts| {
+            debug!("scheduler: {:?}", counts);
+            self.config.wanted(counts, now.time())
+        })
+        .await;
+
+        match res {
+            Ok(Some(0)) => {}
+            Ok(Some(n)) => {
+                info!("scheduler queued {} random animations", n);
+                self.queue.wake();
+            }
+            Ok(None) => debug!("another daemon is scheduling right now"),
+            Err(e) => error!("scheduler couldn't queue random animations: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(t: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(t, "%H:%M").unwrap()
+    }
+
+    fn counts(user_pending: i64, random_queued: i64, random_today: i64) -> SchedulerCounts {
+        SchedulerCounts {
+            user_pending,
+            random_queued,
+            random_today,
+        }
+    }
+
+    fn config(rules: &[&str], daily_quota: Option<i64>) -> SchedulerConfig {
+        SchedulerConfig {
+            rules: rules.iter().map(|r| r.parse().unwrap()).collect(),
+            daily_quota,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_rules() {
+        let rule: ScheduleRule = "keep:3".parse().unwrap();
+        assert_eq!(rule.target, ScheduleTarget::Keep(3));
+        assert_eq!(rule.window, None);
+
+        let rule: ScheduleRule = "daily:10@22:30-06:00".parse().unwrap();
+        assert_eq!(rule.target, ScheduleTarget::Daily(10));
+        assert_eq!(rule.window, Some((time("22:30"), time("06:00"))));
+        assert_eq!(rule.to_string(), "daily:10@22:30-06:00");
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let invalid = [
+            "keep",
+            "keep:",
+            "keep:x",
+            "hourly:3",
+            "keep:3@01:00",
+            "keep:3@1-2",
+            "keep:3@25:00-06:00",
+        ];
+        for rule in invalid {
+            assert!(rule.parse::<ScheduleRule>().is_err(), "{} parsed", rule);
+        }
+    }
+
+    #[test]
+    fn rejects_counts_below_one() {
+        for rule in ["keep:0", "keep:-3", "daily:0", "daily:-1@01:00-06:00"] {
+            let e = rule.parse::<ScheduleRule>().unwrap_err();
+            assert!(e.contains("at least 1"), "{}: {}", rule, e);
+        }
+        assert!("keep:1".parse::<ScheduleRule>().is_ok());
+    }
+
+    #[test]
+    fn rules_roundtrip_through_serde() {
+        let config: SchedulerConfig = toml::from_str(r#"rules = ["keep:2@01:00-06:00"]"#).unwrap();
+        assert_eq!(config.rules[0].target, ScheduleTarget::Keep(2));
+        assert!(toml::to_string(&config).unwrap().contains(r#""keep:2@01:00-06:00""#));
+    }
+
+    #[test]
+    fn active_within_window() {
+        let always: ScheduleRule = "keep:1".parse().unwrap();
+        assert!(always.active(time("12:00")));
+
+        let night: ScheduleRule = "keep:1@01:00-06:00".parse().unwrap();
+        assert!(night.active(time("01:00")));
+        assert!(night.active(time("05:59")));
+        assert!(!night.active(time("06:00")));
+        assert!(!night.active(time("00:59")));
+    }
+
+    #[test]
+    fn active_across_midnight() {
+        let rule: ScheduleRule = "keep:1@22:00-02:00".parse().unwrap();
+        assert!(rule.active(time("22:00")));
+        assert!(rule.active(time("23:59")));
+        assert!(rule.active(time("00:00")));
+        assert!(rule.active(time("01:59")));
+        assert!(!rule.active(time("02:00")));
+        assert!(!rule.active(time("12:00")));
+    }
+
+    #[test]
+    fn keep_fills_up_the_queue() {
+        let rule: ScheduleRule = "keep:3".parse().unwrap();
+        assert_eq!(rule.wanted(&counts(0, 0, 0)), 3);
+        assert_eq!(rule.wanted(&counts(0, 2, 5)), 1);
+        assert_eq!(rule.wanted(&counts(0, 3, 5)), 0);
+    }
+
+    #[test]
+    fn daily_queues_one_at_a_time() {
+        let rule: ScheduleRule = "daily:2".parse().unwrap();
+        assert_eq!(rule.wanted(&counts(0, 0, 0)), 1);
+        assert_eq!(rule.wanted(&counts(0, 1, 1)), 0);
+        assert_eq!(rule.wanted(&counts(0, 0, 1)), 1);
+        assert_eq!(rule.wanted(&counts(0, 0, 2)), 0);
+    }
+
+    #[test]
+    fn nothing_while_users_wait() {
+        let config = config(&["keep:3"], None);
+        assert_eq!(config.wanted(&counts(1, 0, 0), time("12:00")), 0);
+    }
+
+    #[test]
+    fn most_demanding_active_rule_wins() {
+        let config = config(&["keep:1", "keep:4@01:00-06:00"], None);
+        assert_eq!(config.wanted(&counts(0, 0, 0), time("12:00")), 1);
+        assert_eq!(config.wanted(&counts(0, 0, 0), time("03:00")), 4);
+    }
+
+    #[test]
+    fn quota_caps_the_day() {
+        let config = config(&["keep:5"], Some(6));
+        assert_eq!(config.wanted(&counts(0, 0, 3), time("12:00")), 3);
+        assert_eq!(config.wanted(&counts(0, 0, 6), time("12:00")), 0);
+        // more than the quota already happened, e.g. after it was lowered
+        assert_eq!(config.wanted(&counts(0, 0, 9), time("12:00")), 0);
+    }
+
+    #[test]
+    fn overshooting_rules_want_nothing() {
+        let config = config(&["keep:2"], None);
+        assert_eq!(config.wanted(&counts(0, 5, 5), time("12:00")), 0);
+    }
+}
@@ -0,0 +1,523 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use sqlx::{Pool, Postgres};
+use tokio::{sync::Notify, task::JoinHandle, time::timeout};
+
+use crate::{
+    config::redact_endpoint,
+    database::{
+        WebhookDelivery, claim_webhook_deliveries, enqueue_webhook,
+        stored_assets, webhook_delivered, webhook_failed, webhook_subject,
+    },
+};
+
+/// Name of the event, e.g. `done`
+pub static EVENT_HEADER: &str = "X-Tarascope-Event";
+/// Id of the delivery, stays the same for every retry
+pub static DELIVERY_HEADER: &str = "X-Tarascope-Delivery";
+/// Unix time the request was signed at
+pub static TIMESTAMP_HEADER: &str = "X-Tarascope-Timestamp";
+/// `sha256=` followed by the hex encoded HMAC of `{timestamp}.{body}`
+pub static SIGNATURE_HEADER: &str = "X-Tarascope-Signature";
+
+/// How long the dispatcher sleeps when there is nothing to deliver
+static POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Number of calls a dispatcher takes at once
+static BATCH_SIZE: i64 = 10;
+
+/// State change of a job a webhook can be sent for
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookEvent {
+    /// the kaleidoscope was rendered and its assets are available
+    Done,
+    /// the job gave up after its last attempt
+    Failed,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::Done => "done",
+            WebhookEvent::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookEndpoint {
+    pub url: String,
+
+    /// Key the payload gets signed with, unsigned if not set
+    #[serde(default)]
+    pub secret: Option<String>,
+
+    /// Events that are sent to this endpoint, all of them by default
+    #[serde(default = "all_events")]
+    pub events: Vec<WebhookEvent>,
+}
+
+fn all_events() -> Vec<WebhookEvent> {
+    vec![WebhookEvent::Done, WebhookEvent::Failed]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookConfig {
+    /// URLs that get a POST on job state changes
+    pub endpoints: Vec<WebhookEndpoint>,
+
+    /// Public URL of the output directory, asset links in the payload are relative without it
+    pub asset_base_url: Option<String>,
+
+    /// How often a call is attempted before it is given up
+    pub max_attempts: i32,
+
+    /// Seconds before the first retry of a failed call, doubled with every further attempt
+    pub retry_backoff_seconds: u64,
+
+    /// Seconds an endpoint gets to answer
+    pub timeout_seconds: u64,
+}
+
+impl WebhookConfig {
+    /// Seconds until a call that failed on its `attempt`th try is sent again,
+    /// `None` once every attempt is used up
+    pub fn retry_delay(&self, attempt: i32) -> Option<f64> {
+        (attempt < self.max_attempts)
+            .then(|| self.retry_backoff_seconds as f64 * 2f64.powi(attempt.max(1) - 1))
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: Vec::new(),
+            asset_base_url: None,
+            max_attempts: 8,
+            retry_backoff_seconds: 30,
+            timeout_seconds: 10,
+        }
+    }
+}
+
+/// Links to the files of a kaleidoscope
+#[derive(Debug, Default, Serialize)]
+pub struct WebhookAssets {
+    pub video: Option<String>,
+    pub gif: Option<String>,
+    pub thumbnail: Option<String>,
+    pub poster: Option<String>,
+    pub contact_sheet: Option<String>,
+}
+
+/// Body of a webhook call
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload {
+    pub event: WebhookEvent,
+    pub id: String,
+    pub kind: String,
+    pub status: String,
+    pub timestamp: DateTime<Utc>,
+    pub parameters: Value,
+    pub assets: WebhookAssets,
+    pub error: Option<String>,
+}
+
+/// HMAC-SHA256 signature of a webhook call, as sent in [`SIGNATURE_HEADER`]
+pub fn sign(secret: &str, timestamp: u64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Sends the configured webhooks. Calls are recorded in the `webhook_deliveries`
+/// table first and delivered by a background task, so they survive restarts
+/// and a failing endpoint never holds up the render queue.
+pub struct Webhooks {
+    pool: Pool<Postgres>,
+    config: WebhookConfig,
+    client: reqwest::Client,
+    wakeup: Notify,
+}
+
+impl Webhooks {
+    pub fn new(pool: Pool<Postgres>, config: WebhookConfig) -> Result<Arc<Self>, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .user_agent(concat!("tarascope/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        Ok(Arc::new(Self {
+            pool,
+            config,
+            client,
+            wakeup: Notify::new(),
+        }))
+    }
+
+    /// Starts delivering, unless no endpoint is configured
+    pub fn spawn(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        if self.config.endpoints.is_empty() {
+            return None;
+        }
+        info!("sending webhooks to {} endpoints", self.config.endpoints.len());
+
+        let webhooks = self.clone();
+        Some(tokio::spawn(async move { webhooks.dispatch().await }))
+    }
+
+    /// Records the calls for a job that reached `event`
+    pub async fn job_finished(&self, id: &String, kind: &str, event: WebhookEvent) {
+        let endpoints: Vec<&WebhookEndpoint> = self
+            .config
+            .endpoints
+            .iter()
+            .filter(|e| e.events.contains(&event))
+            .collect();
+        if endpoints.is_empty() {
+            return;
+        }
+
+        let subject = match webhook_subject(&self.pool, id).await.map_err(|e| e.to_string()) {
+            Ok(Some(subject)) => subject,
+            Ok(None) => return,
+            Err(e) => {
+                error!("couldn't look up {} for its webhooks: {}", id, e);
+                return;
+            }
+        };
+
+        // assets are only linked once the kaleidoscope is done, and only those that exist
+        let assets = match event {
+            WebhookEvent::Done => {
+                let stored = match stored_assets(&self.pool, id).await {
+                    Ok(stored) => stored,
+                    Err(e) => {
+                        error!("couldn't look up the stored assets of {} for its webhooks: {}", id, e);
+                        return;
+                    }
+                };
+                // stored assets may be gone from the output directory, their local path is a fallback
+                let url = |name: &str, local: Option<String>| match stored.iter().find(|a| a.name == name) {
+                    Some(asset) => Some(asset.url.clone()),
+                    None => local.map(|p| self.asset_url(&p)),
+                };
+                WebhookAssets {
+                    video: url("video", subject.video),
+                    gif: url("gif", subject.gif),
+                    thumbnail: url("thumbnail", subject.thumbnail),
+                    poster: url("poster", subject.poster),
+                    contact_sheet: url("contact_sheet", subject.contact_sheet),
+                }
+            }
+            WebhookEvent::Failed => WebhookAssets::default(),
+        };
+        let payload = WebhookPayload {
+            event,
+            id: subject.id,
+            kind: String::from(kind),
+            status: subject.status,
+            timestamp: Utc::now(),
+            parameters: subject.parameters,
+            assets,
+            error: subject.error,
+        };
+        let payload = match serde_json::to_value(&payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                error!("couldn't serialize webhook payload of {}: {}", id, e);
+                return;
+            }
+        };
+
+        for endpoint in endpoints {
+            let res = enqueue_webhook(&self.pool, id, event.as_str(), &endpoint.url, &payload).await;
+            if let Err(e) = res {
+                error!("couldn't record webhook to {}: {}", redact_endpoint(&endpoint.url), e);
+            }
+        }
+
+        self.wakeup.notify_waiters();
+    }
+
+    fn asset_url(&self, path: &str) -> String {
+        match &self.config.asset_base_url {
+            Some(base) => format!("{}/{}", base.trim_end_matches('/'), path),
+            None => String::from(path),
+        }
+    }
+
+    /// Delivers due calls until the daemon stops
+    async fn dispatch(&self) {
+        // a call that hangs is taken over once the request timed out for sure
+        let lease = (self.config.timeout_seconds + 30) as f64;
+        loop {
+            let notified = self.wakeup.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let deliveries = claim_webhook_deliveries(&self.pool, BATCH_SIZE, lease)
+                .await
+                .map_err(|e| e.to_string());
+
+            match deliveries {
+                Ok(deliveries) if !deliveries.is_empty() => {
+                    for delivery in deliveries {
+                        self.deliver(delivery).await;
+                    }
+                }
+                Ok(_) => {
+                    let _ = timeout(POLL_INTERVAL, notified).await;
+                }
+                Err(e) => {
+                    error!("couldn't claim webhook deliveries: {}", e);
+                    let _ = timeout(POLL_INTERVAL, notified).await;
+                }
+            }
+        }
+    }
+
+    /// Sends a single call and records the result
+    async fn deliver(&self, delivery: WebhookDelivery) {
+        let endpoint = redact_endpoint(&delivery.url);
+        debug!("delivering webhook {} to {}", delivery.id, endpoint);
+        let result = self.send(&delivery).await;
+
+        let res = match &result {
+            Ok(status) => webhook_delivered(&self.pool, delivery.id, *status as i32)
+                .await
+                .map(|_| false),
+            Err((status, reason)) => {
+                let retry_in = self.config.retry_delay(delivery.attempts);
+                webhook_failed(&self.pool, delivery.id, status.map(|s| s as i32), reason, retry_in)
+                    .await
+                    .map(|_| retry_in.is_some())
+            }
+        }
+        .map_err(|e| e.to_string());
+
+        match (result, res) {
+            (Ok(_), Ok(_)) => debug!("webhook {} delivered", delivery.id),
+            (Err((_, reason)), Ok(true)) => {
+                warn!("webhook {} to {} failed, will be retried: {}", delivery.id, endpoint, reason)
+            }
+            (Err((_, reason)), Ok(false)) => {
+                error!("webhook {} to {} gave up: {}", delivery.id, endpoint, reason)
+            }
+            (_, Err(e)) => error!("couldn't record webhook delivery {}: {}", delivery.id, e),
+        }
+    }
+
+    /// Posts the payload, any status outside 2xx counts as failure
+    async fn send(&self, delivery: &WebhookDelivery) -> Result<u16, (Option<u16>, String)> {
+        let Some(endpoint) = self.config.endpoints.iter().find(|e| e.url == delivery.url) else {
+            return Err((None, String::from("endpoint is no longer configured")));
+        };
+
+        let body = serde_json::to_vec(&delivery.payload).map_err(|e| (None, e.to_string()))?;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+
+        let mut request = self
+            .client
+            .post(&endpoint.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, &delivery.event)
+            .header(DELIVERY_HEADER, delivery.id.to_string())
+            .header(TIMESTAMP_HEADER, timestamp.to_string());
+        if let Some(secret) = &endpoint.secret {
+            request = request.header(SIGNATURE_HEADER, sign(secret, timestamp, &body));
+        }
+
+        // the error would name the url, which may hold the token of the hook
+        let response = request
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| (None, e.without_url().to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(status.as_u16())
+        } else {
+            Err((Some(status.as_u16()), format!("endpoint answered {}", status)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use sqlx::postgres::PgPoolOptions;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// Answers a single request with `status` and hands out the request it got
+    async fn stand_in(status: u16) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // the request is complete once the body behind the headers has its length
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.to_lowercase().strip_prefix("content-length: ").map(String::from))
+                        .and_then(|l| l.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
+                    }
+                }
+                if n == 0 {
+                    break;
+                }
+            }
+            let response = format!("HTTP/1.1 {} Stand-In\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (url, handle)
+    }
+
+    fn webhooks(url: &str, secret: Option<&str>) -> Arc<Webhooks> {
+        // never connects, sending doesn't touch the database
+        let pool = PgPoolOptions::new().connect_lazy("postgres://localhost/tarascope").unwrap();
+        let config = WebhookConfig {
+            endpoints: vec![WebhookEndpoint {
+                url: String::from(url),
+                secret: secret.map(String::from),
+                events: all_events(),
+            }],
+            ..Default::default()
+        };
+        Webhooks::new(pool, config).unwrap()
+    }
+
+    fn delivery(url: &str) -> WebhookDelivery {
+        WebhookDelivery {
+            id: 7,
+            event: String::from("done"),
+            url: String::from(url),
+            payload: json!({ "event": "done", "id": "abc" }),
+            attempts: 1,
+        }
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines().find_map(|line| {
+            let (key, value) = line.split_once(": ")?;
+            key.eq_ignore_ascii_case(name).then_some(value)
+        })
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        // echo -n '1700000000.{"event":"done"}' | openssl dgst -sha256 -hmac secret
+        assert_eq!(
+            sign("secret", 1700000000, br#"{"event":"done"}"#),
+            "sha256=06e89d3a5dbe519c984052e9b2a41a1621954c9573377a28c9e483477542d6ca"
+        );
+        assert_ne!(sign("secret", 1700000001, b"{}"), sign("secret", 1700000000, b"{}"));
+        assert_ne!(sign("other", 1700000000, b"{}"), sign("secret", 1700000000, b"{}"));
+    }
+
+    #[test]
+    fn retries_with_backoff_until_given_up() {
+        let config = WebhookConfig {
+            max_attempts: 4,
+            retry_backoff_seconds: 30,
+            ..Default::default()
+        };
+        assert_eq!(config.retry_delay(1), Some(30.0));
+        assert_eq!(config.retry_delay(2), Some(60.0));
+        assert_eq!(config.retry_delay(3), Some(120.0));
+        assert_eq!(config.retry_delay(4), None);
+        assert_eq!(config.retry_delay(5), None);
+    }
+
+    #[test]
+    fn single_attempt_is_never_retried() {
+        let config = WebhookConfig {
+            max_attempts: 1,
+            ..Default::default()
+        };
+        assert_eq!(config.retry_delay(1), None);
+    }
+
+    #[tokio::test]
+    async fn sends_signed_payload() {
+        let (url, request) = stand_in(204).await;
+        let webhooks = webhooks(&url, Some("secret"));
+
+        assert_eq!(webhooks.send(&delivery(&url)).await, Ok(204));
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /hook "));
+        assert_eq!(header(&request, EVENT_HEADER), Some("done"));
+        assert_eq!(header(&request, DELIVERY_HEADER), Some("7"));
+        assert_eq!(header(&request, "content-type"), Some("application/json"));
+
+        let (_, body) = request.split_once("\r\n\r\n").unwrap();
+        let timestamp: u64 = header(&request, TIMESTAMP_HEADER).unwrap().parse().unwrap();
+        assert_eq!(
+            header(&request, SIGNATURE_HEADER),
+            Some(sign("secret", timestamp, body.as_bytes()).as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn unsigned_without_secret() {
+        let (url, request) = stand_in(200).await;
+        assert_eq!(webhooks(&url, None).send(&delivery(&url)).await, Ok(200));
+        assert_eq!(header(&request.await.unwrap(), SIGNATURE_HEADER), None);
+    }
+
+    #[tokio::test]
+    async fn error_status_fails() {
+        let (url, request) = stand_in(503).await;
+        let result = webhooks(&url, None).send(&delivery(&url)).await;
+        request.await.unwrap();
+        assert!(matches!(result, Err((Some(503), _))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn unreachable_endpoint_fails() {
+        // nothing listens on the port once the listener is gone
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+
+        let result = webhooks(&url, None).send(&delivery(&url)).await;
+        assert!(matches!(result, Err((None, _))), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn unknown_endpoint_fails() {
+        let webhooks = webhooks("http://127.0.0.1:9/hook", None);
+        let result = webhooks.send(&delivery("http://127.0.0.1:9/other")).await;
+        assert!(matches!(result, Err((None, _))), "{:?}", result);
+    }
+}
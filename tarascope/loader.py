@@ -1,4 +1,5 @@
 # blender -b -Y -P loader.py
+import os
 import sys
 import bpy # type: ignore
 import base64
@@ -58,6 +59,25 @@ def post_render(scene):
     back_channel.write(status + "\n")
     back_channel.flush()
 
+def add_exr_output(scene, path):
+    # writes a 16 bit copy of every frame next to the png, the "#" in the path
+    # get replaced by the frame number like they do for the regular output
+    scene.use_nodes = True
+    tree = scene.node_tree
+    layers = next((n for n in tree.nodes if n.type == "R_LAYERS"), None)
+    if layers is None:
+        layers = tree.nodes.new("CompositorNodeRLayers")
+    output = tree.nodes.new("CompositorNodeOutputFile")
+    output.base_path = os.path.dirname(path)
+    output.file_slots[0].path = os.path.basename(path)
+    output.format.file_format = "OPEN_EXR"
+    output.format.color_depth = "16"
+    tree.links.new(layers.outputs["Image"], output.inputs[0])
+
+exr_path = os.environ.get("TARASCOPE_EXR_FRAMES")
+if exr_path:
+    add_exr_output(bpy.context.scene, exr_path)
+
 bpy.app.handlers.render_init.clear()
 bpy.app.handlers.render_init.append(r_init)
 bpy.app.handlers.render_post.clear()
@@ -0,0 +1,360 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use clap_derive::{Parser, ValueEnum};
+use image::{
+    DynamicImage, GenericImage, ImageFormat, RgbImage,
+    codecs::jpeg::JpegEncoder,
+    imageops::{FilterType, colorops::grayscale},
+};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::RenderJobDirectories;
+
+/// Decides which frame of a job is used as its poster
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum PosterStrategy {
+    /// Frame in the middle of the sequence
+    Middle,
+
+    /// Frame with the highest image score (contrast, saturation, edges, symmetry)
+    HighestScore,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThumbnailFormat {
+    Jpeg,
+    Webp,
+}
+
+impl ThumbnailFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ThumbnailFormat::Jpeg => "jpg",
+            ThumbnailFormat::Webp => "webp",
+        }
+    }
+}
+
+#[derive(Debug, Parser, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AssetArgs {
+    /// Strategy used to pick the poster frame
+    #[arg(long, value_enum, default_value_t = PosterStrategy::Middle)]
+    pub poster_strategy: PosterStrategy,
+
+    /// Image format of the thumbnail
+    #[arg(long, value_enum, default_value_t = ThumbnailFormat::Jpeg)]
+    pub thumbnail_format: ThumbnailFormat,
+
+    /// Width and height the thumbnail gets fitted into
+    #[arg(long, default_value_t = 256)]
+    pub thumbnail_size: u32,
+
+    /// Number of evenly spaced frames on the contact sheet
+    #[arg(long, default_value_t = 16)]
+    pub contact_sheet_frames: usize,
+
+    /// Width of a single tile on the contact sheet
+    #[arg(long, default_value_t = 256)]
+    pub contact_sheet_tile: u32,
+
+    /// Maximum number of frames that get scored by the highest-score strategy
+    #[arg(long, default_value_t = 30)]
+    pub score_candidates: usize,
+}
+
+impl Default for AssetArgs {
+    fn default() -> Self {
+        Self {
+            poster_strategy: PosterStrategy::Middle,
+            thumbnail_format: ThumbnailFormat::Jpeg,
+            thumbnail_size: 256,
+            contact_sheet_frames: 16,
+            contact_sheet_tile: 256,
+            score_candidates: 30,
+        }
+    }
+}
+
+/// Assets generated for a job. All paths are relative to the output directory,
+/// the same way the showcase view references videos (`<id>/video.mp4`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobAssets {
+    pub thumbnail: String,
+    pub poster: String,
+    pub poster_frame: i32,
+    pub contact_sheet: Option<String>,
+}
+
+/// Generates thumbnail, poster and contact sheet for the rendered frames of a job.
+///
+/// Image processing is cpu bound, so it runs on the blocking thread pool.
+pub async fn generate_assets(
+    dirs: &RenderJobDirectories,
+    args: &AssetArgs,
+) -> io::Result<JobAssets> {
+    let frames = dirs.frame_files()?;
+    let folder = PathBuf::from(dirs.project_folder_path());
+    let id = dirs.get_id();
+    let args = args.clone();
+
+    tokio::task::spawn_blocking(move || generate_assets_blocking(&id, &folder, &frames, &args))
+        .await
+        .map_err(io::Error::other)?
+}
+
+fn generate_assets_blocking(
+    id: &str,
+    folder: &Path,
+    frames: &[PathBuf],
+    args: &AssetArgs,
+) -> io::Result<JobAssets> {
+    if frames.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "job has no rendered frames",
+        ));
+    }
+
+    let poster_index = match args.poster_strategy {
+        PosterStrategy::Middle => frames.len() / 2,
+        PosterStrategy::HighestScore => highest_scoring_frame(frames, args.score_candidates)?,
+    };
+    info!("using frame {} as poster", poster_index);
+
+    let poster = open_frame(&frames[poster_index])?;
+
+    let poster_name = "poster.jpg";
+    write_jpeg(&poster, &folder.join(poster_name), 90)?;
+
+    let thumbnail_name = format!("thumbnail.{}", args.thumbnail_format.extension());
+    let thumbnail = poster.thumbnail(args.thumbnail_size, args.thumbnail_size);
+    match args.thumbnail_format {
+        ThumbnailFormat::Jpeg => write_jpeg(&thumbnail, &folder.join(&thumbnail_name), 85)?,
+        ThumbnailFormat::Webp => DynamicImage::ImageRgb8(thumbnail.to_rgb8())
+            .save_with_format(folder.join(&thumbnail_name), ImageFormat::WebP)
+            .map_err(io::Error::other)?,
+    }
+
+    // a contact sheet of a single still frame is just the poster
+    let contact_sheet = if frames.len() > 1 && args.contact_sheet_frames > 0 {
+        let name = "contact_sheet.jpg";
+        let sheet = contact_sheet(frames, args.contact_sheet_frames, args.contact_sheet_tile)?;
+        write_jpeg(&sheet, &folder.join(name), 85)?;
+        Some(format!("{}/{}", id, name))
+    } else {
+        None
+    };
+
+    Ok(JobAssets {
+        thumbnail: format!("{}/{}", id, thumbnail_name),
+        poster: format!("{}/{}", id, poster_name),
+        poster_frame: frame_number(&frames[poster_index]).unwrap_or(poster_index as i32),
+        contact_sheet,
+    })
+}
+
+fn open_frame(path: &Path) -> io::Result<DynamicImage> {
+    image::open(path).map_err(io::Error::other)
+}
+
+fn write_jpeg(img: &DynamicImage, path: &Path, quality: u8) -> io::Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    let encoder = JpegEncoder::new_with_quality(writer, quality);
+    DynamicImage::ImageRgb8(img.to_rgb8())
+        .write_with_encoder(encoder)
+        .map_err(io::Error::other)
+}
+
+/// Parses the frame number out of a blender output file name (frame_00042.png)
+fn frame_number(path: &Path) -> Option<i32> {
+    path.file_stem()?
+        .to_str()?
+        .strip_prefix("frame_")?
+        .parse()
+        .ok()
+}
+
+/// Returns `count` indices spread evenly over `len` elements
+fn evenly_spaced(len: usize, count: usize) -> Vec<usize> {
+    let count = count.min(len);
+    (0..count).map(|i| i * len / count).collect()
+}
+
+fn contact_sheet(frames: &[PathBuf], count: usize, tile: u32) -> io::Result<DynamicImage> {
+    let indices = evenly_spaced(frames.len(), count);
+    let columns = (indices.len() as f64).sqrt().ceil() as u32;
+    let rows = (indices.len() as u32).div_ceil(columns);
+
+    let mut sheet: Option<RgbImage> = None;
+    for (n, index) in indices.into_iter().enumerate() {
+        let frame = open_frame(&frames[index])?;
+        let frame = frame.resize(tile, tile, FilterType::Triangle).to_rgb8();
+
+        // tile height follows the aspect ratio of the first frame
+        let sheet =
+            sheet.get_or_insert_with(|| RgbImage::new(columns * tile, rows * frame.height()));
+        let x = (n as u32 % columns) * tile;
+        let y = (n as u32 / columns) * frame.height();
+        sheet.copy_from(&frame, x, y).map_err(io::Error::other)?;
+    }
+
+    Ok(DynamicImage::ImageRgb8(sheet.unwrap_or_default()))
+}
+
+fn highest_scoring_frame(frames: &[PathBuf], candidates: usize) -> io::Result<usize> {
+    let mut best = (frames.len() / 2, f32::MIN);
+    for index in evenly_spaced(frames.len(), candidates.max(1)) {
+        let score = score_image(&open_frame(&frames[index])?);
+        debug!("frame {} scored {}", index, score);
+        if score > best.1 {
+            best = (index, score);
+        }
+    }
+    Ok(best.0)
+}
+
+/// Rust port of `rank/rank.py`. Scores a frame by contrast, saturation,
+/// edge energy and left/right symmetry.
+fn score_image(img: &DynamicImage) -> f32 {
+    // scoring does not need the full resolution
+    let rgb = img.resize(512, 512, FilterType::Triangle).to_rgb8();
+    let gray = grayscale(&rgb);
+    let (w, h) = gray.dimensions();
+    let pixels = (w * h) as f32;
+
+    // 1. contrast
+    let mean = gray.pixels().map(|p| p.0[0] as f32).sum::<f32>() / pixels;
+    let variance = gray
+        .pixels()
+        .map(|p| (p.0[0] as f32 - mean).powi(2))
+        .sum::<f32>()
+        / pixels;
+    let contrast = variance.sqrt();
+
+    // 2. saturation (HSV, scaled to 0-255 like opencv)
+    let saturation = rgb
+        .pixels()
+        .map(|p| {
+            let max = *p.0.iter().max().unwrap() as f32;
+            let min = *p.0.iter().min().unwrap() as f32;
+            if max == 0.0 { 0.0 } else { (max - min) / max * 255.0 }
+        })
+        .sum::<f32>()
+        / pixels;
+
+    // 3. edge energy, thresholded gradient magnitude instead of canny
+    let mut edges = 0.0;
+    for y in 1..h.saturating_sub(1) {
+        for x in 1..w.saturating_sub(1) {
+            let gx = gray.get_pixel(x + 1, y).0[0] as f32 - gray.get_pixel(x - 1, y).0[0] as f32;
+            let gy = gray.get_pixel(x, y + 1).0[0] as f32 - gray.get_pixel(x, y - 1).0[0] as f32;
+            if (gx * gx + gy * gy).sqrt() > 100.0 {
+                edges += 255.0;
+            }
+        }
+    }
+    let edge_energy = edges / pixels;
+
+    // 4. symmetry
+    let mut diff = 0.0;
+    for y in 0..h {
+        for x in 0..w / 2 {
+            let l = rgb.get_pixel(x, y).0;
+            let r = rgb.get_pixel(w - 1 - x, y).0;
+            diff += l
+                .iter()
+                .zip(r.iter())
+                .map(|(a, b)| (*a as f32 - *b as f32).abs())
+                .sum::<f32>();
+        }
+    }
+    let symmetry = 1.0 - diff / ((w / 2).max(1) * h * 3) as f32 / 255.0;
+
+    0.4 * contrast + 0.2 * saturation + 0.3 * edge_energy + 0.1 * symmetry
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbaImage};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// Writes `count` 64x32 frames, frame n is filled with the gray value n * 10
+    fn frames(dir: &TempDir, count: usize) -> Vec<PathBuf> {
+        (1..=count)
+            .map(|n| {
+                let path = dir.path().join(format!("frame_{:05}.png", n));
+                let gray = (n * 10) as u8;
+                RgbaImage::from_pixel(64, 32, image::Rgba([gray, gray, gray, 255]))
+                    .save(&path)
+                    .unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spreads_indices_evenly() {
+        assert_eq!(evenly_spaced(10, 5), [0, 2, 4, 6, 8]);
+        assert_eq!(evenly_spaced(10, 3), [0, 3, 6]);
+        assert_eq!(evenly_spaced(3, 16), [0, 1, 2]);
+        assert_eq!(evenly_spaced(10, 0), Vec::<usize>::new());
+        assert_eq!(evenly_spaced(0, 4), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn parses_frame_numbers() {
+        assert_eq!(frame_number(Path::new("/out/job/frame_00042.png")), Some(42));
+        assert_eq!(frame_number(Path::new("frame_00001.exr")), Some(1));
+        assert_eq!(frame_number(Path::new("poster.jpg")), None);
+        assert_eq!(frame_number(Path::new("frame_last.png")), None);
+    }
+
+    #[test]
+    fn lays_out_the_contact_sheet_in_a_square_grid() {
+        let dir = TempDir::new().unwrap();
+        let frames = frames(&dir, 10);
+
+        // 5 tiles fill a 3x2 grid, the tiles keep the 2:1 aspect ratio of the frames
+        let sheet = contact_sheet(&frames, 5, 32).unwrap().to_rgb8();
+        assert_eq!(sheet.dimensions(), (3 * 32, 2 * 16));
+
+        // tiles run left to right, top to bottom and show frames 1, 3, 5, 7 and 9
+        let tile = |column: u32, row: u32| sheet.get_pixel(column * 32 + 16, row * 16 + 8).0[0];
+        assert_eq!([tile(0, 0), tile(1, 0), tile(2, 0), tile(0, 1), tile(1, 1)], [10, 30, 50, 70, 90]);
+        // the last cell of the grid stays empty
+        assert_eq!(*sheet.get_pixel(2 * 32 + 16, 16 + 8), Rgb([0, 0, 0]));
+    }
+
+    #[test]
+    fn generates_every_asset() {
+        let dir = TempDir::new().unwrap();
+        let frames = frames(&dir, 4);
+
+        let assets = generate_assets_blocking("job", dir.path(), &frames, &AssetArgs::default()).unwrap();
+        assert_eq!(assets.poster, "job/poster.jpg");
+        assert_eq!(assets.poster_frame, 3);
+        assert_eq!(assets.contact_sheet.as_deref(), Some("job/contact_sheet.jpg"));
+        assert!(dir.path().join("contact_sheet.jpg").exists());
+        assert!(dir.path().join("poster.jpg").exists());
+    }
+
+    #[test]
+    fn single_frames_get_no_contact_sheet() {
+        let dir = TempDir::new().unwrap();
+        let frames = frames(&dir, 1);
+
+        let assets = generate_assets_blocking("job", dir.path(), &frames, &AssetArgs::default()).unwrap();
+        assert_eq!(assets.poster_frame, 1);
+        assert_eq!(assets.contact_sheet, None);
+    }
+}
@@ -1,8 +1,16 @@
-use std::io::Error;
+use std::error::Error;
 
 use clap::{Parser, command};
 use clap_derive::{Parser, Subcommand};
-use tarascope::{CommandType, Tarascope, encoder::stitch_video, shader::KaleidoArgs};
+use tarascope::{
+    CommandType, Tarascope,
+    encoder::{
+        EncodeArgs,
+        archive::{ArchiveArgs, create_archive},
+        stitch_video,
+    },
+    shader::KaleidoArgs,
+};
 use serde::Serialize;
 use tokio::sync::mpsc::unbounded_channel;
 
@@ -16,6 +24,13 @@ struct CliArgs {
 
     #[arg(short, long)]
     output_dir: String,
+
+    /// Also render every frame as 16 bit EXR
+    #[arg(long)]
+    exr_frames: bool,
+
+    #[clap(flatten)]
+    encode: EncodeArgs,
 }
 
 #[derive(Debug, Subcommand, Clone, Serialize)]
@@ -25,29 +40,51 @@ enum CliModes {
 
     /// Create a parameterized kaleidoscope
     Custom(KaleidoArgs),
+
+    /// Package the frames and parameters of an already rendered job into an archive
+    Archive {
+        /// Id of the job
+        id: String,
+
+        #[clap(flatten)]
+        archive: ArchiveArgs,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
+async fn main() -> Result<(), Box<dyn Error>> {
     let args = CliArgs::parse();
-    let (sender, receiver) = unbounded_channel();
-
-    let tarascopes = Tarascope::new(String::from(args.output_dir));
+    let tarascopes = Tarascope::new(String::from(args.output_dir)).with_exr_frames(args.exr_frames);
 
     let kargs = match args.mode {
         CliModes::Random => KaleidoArgs::random(),
         CliModes::Custom(kaleido_args) => kaleido_args,
+        CliModes::Archive { id, archive } => {
+            let path = create_archive(&tarascopes.paths_for_job(&id), &archive).await?;
+            println!("{}", path.display());
+            return Ok(());
+        }
     };
 
+    let (sender, mut receiver) = unbounded_channel::<String>();
+
+    // print frame and encode status while the job is running
+    tokio::spawn(async move {
+        while let Some(msg) = receiver.recv().await {
+            println!("{}", msg);
+        }
+    });
+
+
     let id = kargs.get_id();
 
     let c = CommandType::Animated(1, 10, kargs);
 
-    let output = tarascopes.start_render(c, sender).await?;
+    let output = tarascopes.start_render(c, sender.clone()).await?;
 
     //let cmd = run_kaleidoscope(output_args.output_dir, &kargs, sender).await?;
     println!("{}", output.exit_status);
 
-    stitch_video(&tarascopes.paths_for_job(&id)).unwrap();
+    stitch_video(&tarascopes.paths_for_job(&id), &args.encode, sender).await?;
     Ok(())
 }
@@ -0,0 +1,313 @@
+use std::{
+    fs::{File, metadata},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use clap_derive::{Parser, ValueEnum};
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::RenderJobDirectories;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+pub enum ArchiveFormat {
+    Zip,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarZst => "application/zstd",
+        }
+    }
+}
+
+#[derive(Debug, Parser, Clone, Serialize, Deserialize)]
+pub struct ArchiveArgs {
+    /// Container format of the archive
+    #[arg(long, value_enum, default_value_t = ArchiveFormat::Zip)]
+    pub format: ArchiveFormat,
+
+    /// Also package the 16 bit frames (frame_#####.exr), they are only there
+    /// if the job was rendered with EXR frames enabled
+    #[arg(long)]
+    pub exr: bool,
+}
+
+impl ArchiveArgs {
+    /// Extension of the archive file, archives with EXR frames get their own file
+    pub fn extension(&self) -> String {
+        match self.exr {
+            true => format!("exr.{}", self.format.extension()),
+            false => self.format.extension().to_string(),
+        }
+    }
+}
+
+impl Default for ArchiveArgs {
+    fn default() -> Self {
+        Self {
+            format: ArchiveFormat::Zip,
+            exr: false,
+        }
+    }
+}
+
+/// Name of the checksum manifest inside the archive
+static MANIFEST_NAME: &str = "manifest.json";
+
+/// Packages the frame sequence and parameters.json of a job into an archive
+/// next to the frames. Returns the path of the archive.
+///
+/// The archive is written to a temporary file and only moved into place once it
+/// is complete, so a reader never sees a partial archive under the final name.
+pub async fn create_archive(dirs: &RenderJobDirectories, args: &ArchiveArgs) -> io::Result<PathBuf> {
+    let mut files = dirs.frame_files()?;
+    if files.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "the job has no frames"));
+    }
+    if args.exr {
+        let exr = dirs.frame_files_with_extension("exr")?;
+        if exr.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "the job has no EXR frames, it has to be rendered with EXR frames enabled",
+            ));
+        }
+        files.extend(exr);
+    }
+    let parameters = PathBuf::from(dirs.parameters_path());
+    files.push(parameters.clone());
+
+    let target = PathBuf::from(dirs.archive_path(&args.extension()));
+    let folder = dirs.project_folder_path();
+    let id = dirs.get_id();
+    let format = args.format;
+
+    tokio::task::spawn_blocking(move || {
+        info!("Packaging {} files of {} into {:?}", files.len(), id, target);
+        let manifest = manifest(&id, &files)?;
+        let tmp = tempfile::Builder::new().prefix(".archive").tempfile_in(folder)?;
+        // temporary files are private, the archive gets the permissions of the other job files
+        tmp.as_file().set_permissions(metadata(&parameters)?.permissions())?;
+        let file = tmp.reopen()?;
+        match format {
+            ArchiveFormat::Zip => write_zip(file, &files, &manifest)?,
+            ArchiveFormat::TarZst => write_tar_zst(file, &files, &manifest)?,
+        }
+        tmp.persist(&target).map_err(|e| e.error)?;
+        Ok(target)
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+fn file_name(path: &Path) -> io::Result<String> {
+    path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Builds the manifest listing every packaged file with its size and sha256 checksum
+fn manifest(id: &str, files: &[PathBuf]) -> io::Result<String> {
+    let mut entries = Vec::new();
+    for path in files {
+        entries.push(json!({
+            "name": file_name(path)?,
+            "size": metadata(path)?.len(),
+            "sha256": sha256(path)?,
+        }));
+    }
+
+    let manifest = json!({
+        "id": id,
+        "files": entries,
+    });
+    serde_json::to_string_pretty(&manifest).map_err(io::Error::other)
+}
+
+fn write_zip(target: File, files: &[PathBuf], manifest: &str) -> io::Result<()> {
+    let mut zip = ZipWriter::new(BufWriter::new(target));
+
+    // frames are already compressed, deflating them again only costs time
+    let stored = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    let deflated = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    for path in files {
+        let name = file_name(path)?;
+        let options = if name.ends_with(".json") { deflated } else { stored };
+        zip.start_file(name, options).map_err(io::Error::other)?;
+        io::copy(&mut File::open(path)?, &mut zip)?;
+    }
+
+    zip.start_file(MANIFEST_NAME, deflated)
+        .map_err(io::Error::other)?;
+    zip.write_all(manifest.as_bytes())?;
+
+    zip.finish().map_err(io::Error::other)?.flush()
+}
+
+fn write_tar_zst(target: File, files: &[PathBuf], manifest: &str) -> io::Result<()> {
+    let encoder = zstd::Encoder::new(BufWriter::new(target), 3)?;
+    let mut tar = tar::Builder::new(encoder);
+
+    for path in files {
+        tar.append_path_with_name(path, file_name(path)?)?;
+    }
+
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(&mut header, MANIFEST_NAME, manifest.as_bytes())?;
+
+    tar.into_inner()?.finish()?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir, read_to_string, write};
+
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A job folder with two frames and its parameters
+    fn job(dir: &TempDir) -> RenderJobDirectories {
+        let dirs = RenderJobDirectories::new("job".to_string(), dir.path().to_string_lossy().into_owned());
+        create_dir(dirs.project_folder_path()).unwrap();
+        write(format!("{}/frame_00002.png", dirs.project_folder_path()), b"second").unwrap();
+        write(format!("{}/frame_00001.png", dirs.project_folder_path()), b"first").unwrap();
+        write(dirs.parameters_path(), b"{}").unwrap();
+        dirs
+    }
+
+    fn names(manifest: &Value) -> Vec<&str> {
+        manifest["files"].as_array().unwrap().iter().map(|f| f["name"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn manifest_lists_sizes_and_checksums() {
+        let dir = TempDir::new().unwrap();
+        let dirs = job(&dir);
+        let mut files = dirs.frame_files().unwrap();
+        files.push(PathBuf::from(dirs.parameters_path()));
+
+        let manifest: Value = serde_json::from_str(&manifest("job", &files).unwrap()).unwrap();
+        assert_eq!(manifest["id"], "job");
+        assert_eq!(names(&manifest), ["frame_00001.png", "frame_00002.png", "parameters.json"]);
+        assert_eq!(manifest["files"][0]["size"], 5);
+        // sha256 of "first"
+        assert_eq!(
+            manifest["files"][0]["sha256"],
+            "a7937b64b8caa58f03721bb6bacf5c78cb235febe0e70b1b84cd99541461a08e"
+        );
+    }
+
+    #[tokio::test]
+    async fn zip_holds_the_frames_and_the_manifest() {
+        let dir = TempDir::new().unwrap();
+        let dirs = job(&dir);
+
+        let path = create_archive(&dirs, &ArchiveArgs::default()).await.unwrap();
+        assert_eq!(path, PathBuf::from(dirs.archive_path("zip")));
+
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut content = String::new();
+        zip.by_name("frame_00002.png").unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "second");
+
+        content.clear();
+        zip.by_name(MANIFEST_NAME).unwrap().read_to_string(&mut content).unwrap();
+        let manifest: Value = serde_json::from_str(&content).unwrap();
+        assert_eq!(names(&manifest), ["frame_00001.png", "frame_00002.png", "parameters.json"]);
+        assert_eq!(zip.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn tar_zst_holds_the_frames_and_the_manifest() {
+        let dir = TempDir::new().unwrap();
+        let dirs = job(&dir);
+        let args = ArchiveArgs {
+            format: ArchiveFormat::TarZst,
+            exr: false,
+        };
+
+        let path = create_archive(&dirs, &args).await.unwrap();
+        let decoder = zstd::Decoder::new(File::open(&path).unwrap()).unwrap();
+        let mut tar = tar::Archive::new(decoder);
+        let mut entries = Vec::new();
+        for entry in tar.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            entries.push((entry.path().unwrap().to_string_lossy().into_owned(), content));
+        }
+
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0], ("frame_00001.png".to_string(), "first".to_string()));
+        assert_eq!(entries[3].0, MANIFEST_NAME);
+        let manifest: Value = serde_json::from_str(&entries[3].1).unwrap();
+        assert_eq!(manifest["files"][2]["name"], "parameters.json");
+    }
+
+    #[tokio::test]
+    async fn refuses_missing_frames() {
+        let dir = TempDir::new().unwrap();
+        let dirs = job(&dir);
+
+        let exr = ArchiveArgs {
+            format: ArchiveFormat::Zip,
+            exr: true,
+        };
+        let e = create_archive(&dirs, &exr).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        dirs.remove_frames().unwrap();
+        let e = create_archive(&dirs, &ArchiveArgs::default()).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(!Path::new(&dirs.archive_path("zip")).exists());
+    }
+
+    #[tokio::test]
+    async fn remove_archives_leaves_the_frames() {
+        let dir = TempDir::new().unwrap();
+        let dirs = job(&dir);
+        create_archive(&dirs, &ArchiveArgs::default()).await.unwrap();
+
+        assert_eq!(dirs.remove_archives().unwrap(), 1);
+        assert!(!Path::new(&dirs.archive_path("zip")).exists());
+        assert_eq!(dirs.frame_files().unwrap().len(), 2);
+        assert_eq!(read_to_string(dirs.parameters_path()).unwrap(), "{}");
+    }
+}
@@ -0,0 +1,522 @@
+use std::process::Stdio;
+
+use clap_derive::{Parser, ValueEnum};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::create_dir_all,
+    io::{self, AsyncBufReadExt, BufReader},
+    process::Command,
+    sync::mpsc::UnboundedSender,
+};
+
+use crate::{
+    EncodeStatus, RenderJobDirectories,
+    error::EncodeError,
+    joblog::{RotatingLog, rotate},
+};
+
+use self::native::stitch_native;
+
+pub mod archive;
+mod native;
+
+/// A single rendition of the adaptive streaming outputs
+struct Rendition {
+    height: u32,
+    bitrate: &'static str,
+    maxrate: &'static str,
+    bufsize: &'static str,
+}
+
+/// Bitrate ladder used for HLS and DASH, highest quality first
+static RENDITIONS: &[Rendition] = &[
+    Rendition {
+        height: 1080,
+        bitrate: "6M",
+        maxrate: "6.6M",
+        bufsize: "12M",
+    },
+    Rendition {
+        height: 720,
+        bitrate: "3M",
+        maxrate: "3.3M",
+        bufsize: "6M",
+    },
+    Rendition {
+        height: 480,
+        bitrate: "1.2M",
+        maxrate: "1.3M",
+        bufsize: "2.4M",
+    },
+];
+
+/// Length of a single streaming segment in seconds
+static SEGMENT_DURATION: &str = "4";
+
+/// Program that turns the rendered frames into videos
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncoderBackend {
+    /// Use ffmpeg if it is installed, the native encoder otherwise
+    #[default]
+    Auto,
+
+    /// mp4, gif and the streaming formats through ffmpeg
+    Ffmpeg,
+
+    /// gif and apng, encoded in rust without any external program
+    Native,
+}
+
+#[derive(Debug, Parser, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncodeArgs {
+    /// Encoder used to stitch the frames
+    #[arg(long, value_enum, default_value_t = EncoderBackend::Auto)]
+    pub backend: EncoderBackend,
+
+    /// Additionally emit an HLS playlist with multiple bitrate renditions
+    #[arg(long)]
+    pub hls: bool,
+
+    /// Additionally emit a DASH manifest with multiple bitrate renditions
+    #[arg(long)]
+    pub dash: bool,
+}
+
+/// Videos that were encoded for a job. Like [`JobAssets`](crate::assets::JobAssets)
+/// all paths are relative to the output directory, formats the backend can't
+/// produce are left out.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VideoAssets {
+    pub video: Option<String>,
+    pub gif: Option<String>,
+    pub apng: Option<String>,
+}
+
+/// Checks whether an ffmpeg executable can be started
+pub async fn ffmpeg_available() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+        .is_ok_and(|s| s.success())
+}
+
+/// Stitches the rendered frames of a job into all output formats enabled in `args`.
+///
+/// Progress of every encoder run is reported as [`EncodeStatus`] through `sender`,
+/// the same channel the renderer uses for its frame status messages.
+/// Returns the videos that were written.
+pub async fn stitch_video(
+    dirs: &RenderJobDirectories,
+    args: &EncodeArgs,
+    sender: UnboundedSender<String>,
+) -> Result<VideoAssets, EncodeError> {
+    if dirs.frame_files()?.is_empty() {
+        return Err(EncodeError::NoFrames);
+    }
+
+    let backend = match args.backend {
+        EncoderBackend::Auto if ffmpeg_available().await => EncoderBackend::Ffmpeg,
+        EncoderBackend::Auto => {
+            warn!("ffmpeg not found, falling back to the native encoder");
+            EncoderBackend::Native
+        }
+        backend => backend,
+    };
+
+    if backend == EncoderBackend::Native {
+        if args.hls || args.dash {
+            warn!("streaming outputs need ffmpeg and are skipped");
+        }
+        stitch_native(dirs, sender).await?;
+        return Ok(VideoAssets {
+            video: None,
+            gif: Some(format!("{}/video.gif", dirs.get_id())),
+            apng: Some(format!("{}/video.apng", dirs.get_id())),
+        });
+    }
+
+    // ffmpeg runs of one attempt share a log, the one of the previous attempt is kept
+    rotate(&dirs.ffmpeg_stderr_path(), dirs.log_limits().keep).await?;
+    stitch_video_mp4(dirs, sender.clone()).await?;
+    stitch_video_gif(dirs, sender.clone()).await?;
+    if args.hls {
+        stitch_video_hls(dirs, sender.clone()).await?;
+    }
+    if args.dash {
+        stitch_video_dash(dirs, sender).await?;
+    }
+    Ok(VideoAssets {
+        video: Some(format!("{}/video.mp4", dirs.get_id())),
+        gif: Some(format!("{}/video.gif", dirs.get_id())),
+        apng: None,
+    })
+}
+
+pub async fn stitch_video_gif(
+    dirs: &RenderJobDirectories,
+    sender: UnboundedSender<String>,
+) -> Result<(), EncodeError> {
+    info!("Stitching gif");
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args([
+        "-y",
+        "-framerate",
+        "60",
+        "-i",
+        format!("{}/frame_%05d.png", dirs.project_folder_path()).as_str(),
+        dirs.gif_path().as_str(),
+    ]);
+
+    run_ffmpeg(&mut cmd, dirs, "gif", sender).await?;
+    info!("gif stitch sucessful");
+    Ok(())
+}
+
+pub async fn stitch_video_mp4(
+    dirs: &RenderJobDirectories,
+    sender: UnboundedSender<String>,
+) -> Result<(), EncodeError> {
+    info!("Stitching Video");
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args([
+        "-y",
+        "-framerate",
+        "60",
+        "-i",
+        format!("{}/frame_%05d.png", dirs.project_folder_path()).as_str(),
+        "-c:v",
+        "libx264",
+        "-pix_fmt",
+        "yuv420p",
+        dirs.video_path().as_str(),
+    ]);
+
+    run_ffmpeg(&mut cmd, dirs, "mp4", sender).await?;
+    info!("video stitch sucessful");
+    Ok(())
+}
+
+/// Appends the inputs, the scaling filter graph and one video encoder per rendition
+fn rendition_args(cmd: &mut Command, dirs: &RenderJobDirectories) {
+    let split: String = (0..RENDITIONS.len()).map(|i| format!("[v{}]", i)).collect();
+    let scales: Vec<String> = RENDITIONS
+        .iter()
+        .enumerate()
+        .map(|(i, r)| format!("[v{i}]scale=w=-2:h='min({},ih)'[v{i}out]", r.height))
+        .collect();
+    let filter = format!(
+        "[0:v]split={}{};{}",
+        RENDITIONS.len(),
+        split,
+        scales.join(";")
+    );
+
+    cmd.args([
+        "-y",
+        "-framerate",
+        "60",
+        "-i",
+        format!("{}/frame_%05d.png", dirs.project_folder_path()).as_str(),
+        "-filter_complex",
+        filter.as_str(),
+    ]);
+
+    for (i, r) in RENDITIONS.iter().enumerate() {
+        cmd.arg("-map")
+            .arg(format!("[v{}out]", i))
+            .arg(format!("-c:v:{}", i))
+            .arg("libx264")
+            .arg(format!("-b:v:{}", i))
+            .arg(r.bitrate)
+            .arg(format!("-maxrate:v:{}", i))
+            .arg(r.maxrate)
+            .arg(format!("-bufsize:v:{}", i))
+            .arg(r.bufsize);
+    }
+
+    // fixed keyframe interval so segments of all renditions line up
+    cmd.args(["-pix_fmt", "yuv420p", "-g", "120", "-sc_threshold", "0"]);
+}
+
+pub async fn stitch_video_hls(
+    dirs: &RenderJobDirectories,
+    sender: UnboundedSender<String>,
+) -> Result<(), EncodeError> {
+    info!("Stitching HLS");
+    // the hls muxer does not create the target folder by itself
+    create_dir_all(dirs.hls_folder_path()).await?;
+
+    run_ffmpeg(&mut hls_command(dirs), dirs, "hls", sender).await?;
+    info!("hls stitch sucessful");
+    Ok(())
+}
+
+/// ffmpeg call that writes one playlist per rendition and the master playlist
+/// at [`RenderJobDirectories::hls_playlist_path`]
+fn hls_command(dirs: &RenderJobDirectories) -> Command {
+    let var_stream_map: Vec<String> = (0..RENDITIONS.len()).map(|i| format!("v:{}", i)).collect();
+
+    let mut cmd = Command::new("ffmpeg");
+    rendition_args(&mut cmd, dirs);
+    cmd.args([
+        "-f",
+        "hls",
+        "-hls_time",
+        SEGMENT_DURATION,
+        "-hls_playlist_type",
+        "vod",
+        "-hls_segment_filename",
+        format!("{}/stream_%v_%03d.ts", dirs.hls_folder_path()).as_str(),
+        "-master_pl_name",
+        "master.m3u8",
+        "-var_stream_map",
+        var_stream_map.join(" ").as_str(),
+        format!("{}/stream_%v.m3u8", dirs.hls_folder_path()).as_str(),
+    ]);
+    cmd
+}
+
+pub async fn stitch_video_dash(
+    dirs: &RenderJobDirectories,
+    sender: UnboundedSender<String>,
+) -> Result<(), EncodeError> {
+    info!("Stitching DASH");
+    create_dir_all(dirs.dash_folder_path()).await?;
+
+    run_ffmpeg(&mut dash_command(dirs), dirs, "dash", sender).await?;
+    info!("dash stitch sucessful");
+    Ok(())
+}
+
+/// ffmpeg call that writes the segments of all renditions and the manifest
+/// at [`RenderJobDirectories::dash_manifest_path`]
+fn dash_command(dirs: &RenderJobDirectories) -> Command {
+    let mut cmd = Command::new("ffmpeg");
+    rendition_args(&mut cmd, dirs);
+    cmd.args([
+        "-f",
+        "dash",
+        "-seg_duration",
+        SEGMENT_DURATION,
+        "-use_template",
+        "1",
+        "-use_timeline",
+        "1",
+        "-adaptation_sets",
+        "id=0,streams=v",
+        "-init_seg_name",
+        "init_$RepresentationID$.m4s",
+        "-media_seg_name",
+        "chunk_$RepresentationID$_$Number%05d$.m4s",
+        dirs.dash_manifest_path().as_str(),
+    ]);
+    cmd
+}
+
+/// Follows the progress ffmpeg writes with `-progress`, blocks of key=value pairs
+/// that are each terminated by a progress key
+struct ProgressParser {
+    id: String,
+    target: &'static str,
+    /// number of frames the encode reads
+    total: usize,
+    /// last frame ffmpeg reported
+    frame: usize,
+}
+
+impl ProgressParser {
+    fn new(id: String, target: &'static str, total: usize) -> Self {
+        Self {
+            id,
+            target,
+            total,
+            frame: 0,
+        }
+    }
+
+    /// Takes a single line of the progress output, returns the status once a block is complete
+    fn parse(&mut self, line: &str) -> Option<EncodeStatus> {
+        match line.trim().split_once('=') {
+            Some(("frame", value)) => {
+                self.frame = value.parse().unwrap_or(self.frame);
+                None
+            }
+            Some(("progress", value)) => {
+                let progress = if value == "end" || self.total == 0 {
+                    1.0
+                } else {
+                    (self.frame as f32 / self.total as f32).min(1.0)
+                };
+                Some(EncodeStatus {
+                    id: self.id.clone(),
+                    target: String::from(self.target),
+                    progress,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Runs ffmpeg with `-progress pipe:1` and forwards the parsed progress to `sender`.
+///
+/// Fails if ffmpeg doesn't exit successfully.
+async fn run_ffmpeg(
+    cmd: &mut Command,
+    dirs: &RenderJobDirectories,
+    target: &'static str,
+    sender: UnboundedSender<String>,
+) -> Result<(), EncodeError> {
+    let mut parser = ProgressParser::new(dirs.get_id(), target, dirs.frame_files()?.len());
+
+    cmd.args(["-progress", "pipe:1", "-nostats"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // a cancelled job drops the encode, ffmpeg must not outlive it
+        .kill_on_drop(true);
+
+    let mut child = cmd.spawn()?;
+
+    let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return Err(io::Error::other("ffmpeg output isn't piped").into());
+    };
+    let mut log = RotatingLog::append(dirs.ffmpeg_stderr_path(), dirs.log_limits()).await?;
+    let stderr_task = tokio::spawn(async move {
+        let mut lines = BufReader::new(stderr).lines();
+        while let Some(line) = lines.next_line().await? {
+            log.write_line(&line).await?;
+        }
+        log.flush().await
+    });
+
+    let mut lines = BufReader::new(stdout).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Some(status) = parser.parse(&line) {
+            debug!("{:?}", status);
+            if let Ok(msg) = serde_json::to_string(&status) {
+                // the receiver going away must not abort the encode
+                let _ = sender.send(msg);
+            }
+        }
+    }
+
+    let status = child.wait().await?;
+    if let Ok(Err(e)) = stderr_task.await {
+        error!("couldn't write ffmpeg log: {}", e);
+    }
+
+    if !status.success() {
+        return Err(EncodeError::Ffmpeg { target, status });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn dirs() -> RenderJobDirectories {
+        RenderJobDirectories::new(String::from("job"), String::from("/out"))
+    }
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.as_std().get_args().map(|a| a.to_string_lossy().into_owned()).collect()
+    }
+
+    /// Value following `flag` in the arguments
+    fn value<'a>(args: &'a [String], flag: &str) -> &'a str {
+        let at = args.iter().position(|a| a == flag).unwrap();
+        &args[at + 1]
+    }
+
+    #[test]
+    fn ladder_runs_from_the_highest_quality_down() {
+        let heights: Vec<u32> = RENDITIONS.iter().map(|r| r.height).collect();
+        assert_eq!(heights, [1080, 720, 480]);
+    }
+
+    #[test]
+    fn every_rendition_gets_its_own_stream() {
+        let args = args(&hls_command(&dirs()));
+        assert_eq!(
+            value(&args, "-filter_complex"),
+            "[0:v]split=3[v0][v1][v2];\
+             [v0]scale=w=-2:h='min(1080,ih)'[v0out];\
+             [v1]scale=w=-2:h='min(720,ih)'[v1out];\
+             [v2]scale=w=-2:h='min(480,ih)'[v2out]"
+        );
+        for (i, r) in RENDITIONS.iter().enumerate() {
+            assert!(args.contains(&format!("[v{}out]", i)));
+            assert_eq!(value(&args, &format!("-b:v:{}", i)), r.bitrate);
+            assert_eq!(value(&args, &format!("-maxrate:v:{}", i)), r.maxrate);
+            assert_eq!(value(&args, &format!("-bufsize:v:{}", i)), r.bufsize);
+        }
+        assert_eq!(value(&args, "-var_stream_map"), "v:0 v:1 v:2");
+    }
+
+    #[test]
+    fn streams_end_up_where_they_are_served_from() {
+        let dirs = dirs();
+        assert_eq!(dirs.hls_playlist_path(), "/out/job/hls/master.m3u8");
+        assert_eq!(dirs.dash_manifest_path(), "/out/job/dash/manifest.mpd");
+
+        // the master playlist is written next to the playlists of the renditions
+        let hls = args(&hls_command(&dirs));
+        let playlists = Path::new(hls.last().unwrap());
+        assert_eq!(playlists.parent().unwrap(), Path::new(&dirs.hls_folder_path()));
+        let master = Path::new(&dirs.hls_folder_path()).join(value(&hls, "-master_pl_name"));
+        assert_eq!(master, Path::new(&dirs.hls_playlist_path()));
+        assert!(value(&hls, "-hls_segment_filename").starts_with(&dirs.hls_folder_path()));
+
+        let dash = args(&dash_command(&dirs));
+        assert_eq!(dash.last().unwrap(), &dirs.dash_manifest_path());
+    }
+
+    /// Feeds the progress output to a parser, returns the progress of every completed block
+    fn progress(total: usize, output: &str) -> Vec<f32> {
+        let mut parser = ProgressParser::new(String::from("job"), "mp4", total);
+        output.lines().filter_map(|line| parser.parse(line)).map(|s| s.progress).collect()
+    }
+
+    #[test]
+    fn parses_ffmpeg_progress() {
+        let output = "frame=30\nfps=29.5\nout_time=00:00:00.500000\nprogress=continue\n\
+                      frame=90\nfps=30.1\nprogress=continue\n\
+                      frame=120\nprogress=end\n";
+        assert_eq!(progress(120, output), [0.25, 0.75, 1.0]);
+    }
+
+    #[test]
+    fn progress_reports_the_job_and_target() {
+        let mut parser = ProgressParser::new(String::from("job"), "gif", 10);
+        assert!(parser.parse("frame=5").is_none());
+        let status = parser.parse(" progress=continue ").unwrap();
+        assert_eq!((status.id.as_str(), status.target.as_str()), ("job", "gif"));
+        assert_eq!(status.progress, 0.5);
+    }
+
+    #[test]
+    fn progress_stays_within_bounds() {
+        // ffmpeg may count more frames than were rendered
+        assert_eq!(progress(10, "frame=30\nprogress=continue\n"), [1.0]);
+        // ffmpeg ends before any frame was counted
+        assert_eq!(progress(0, "progress=continue\n"), [1.0]);
+        assert_eq!(progress(10, "progress=continue\n"), [0.0]);
+    }
+
+    #[test]
+    fn progress_ignores_unexpected_lines() {
+        let output = "frame=4\nframe=N/A\nnot a pair\n=\nprogress=continue\n";
+        assert_eq!(progress(8, output), [0.5]);
+    }
+}
@@ -0,0 +1,39 @@
+use std::process::ExitStatus;
+
+use thiserror::Error;
+use tokio::io;
+
+/// Why a render couldn't be started or didn't finish
+#[derive(Debug, Error)]
+pub enum RenderError {
+    #[error("couldn't create the project directory {path}: {source}")]
+    ProjectDirectory { path: String, source: io::Error },
+
+    #[error("couldn't extract the blender project: {0}")]
+    Extract(io::Error),
+
+    #[error("couldn't write the parameters: {0}")]
+    Parameters(io::Error),
+
+    #[error("couldn't run blender: {0}")]
+    Blender(io::Error),
+
+    #[error("blender exited with {0}")]
+    Exit(ExitStatus),
+}
+
+/// Why the frames of a job couldn't be stitched
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("the job has no rendered frames")]
+    NoFrames,
+
+    #[error("ffmpeg exited with {status} while encoding the {target}")]
+    Ffmpeg {
+        target: &'static str,
+        status: ExitStatus,
+    },
+
+    #[error("couldn't encode: {0}")]
+    Io(#[from] io::Error),
+}
@@ -4,16 +4,18 @@ use std::{
 };
 
 use command_fds::{CommandFdExt, FdMapping};
-use log::{error, info};
 use tokio::{
-    fs::File,
-    io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{self, AsyncBufReadExt, AsyncRead, BufReader},
     net::unix::pipe::{self},
     process::Command,
     sync::{Mutex, mpsc::UnboundedSender},
 };
+use tracing::{Instrument, Level, Span, debug, error, trace, warn};
 
-use crate::RenderJobDirectories;
+use crate::{
+    RenderJobDirectories,
+    joblog::{LogLimits, RotatingLog, classify},
+};
 
 /// Runs the blender executor
 pub async fn run(
@@ -30,61 +32,25 @@ pub async fn run(
         parent_fd: writer.into_blocking_fd()?,
         child_fd: 7,
     }])
-    .unwrap()
+    .map_err(io::Error::other)?
     .stdout(Stdio::piped())
-    .stderr(Stdio::piped());
+    .stderr(Stdio::piped())
+    // cancelling a job drops this future, which has to take blender down with it
+    .kill_on_drop(true);
 
     let mut ccmd = cmd.spawn()?;
 
-    let stdout = ccmd.stdout.take().unwrap();
-    let stdout_path = dirs.blender_stdout_path();
-    let stdout_task = tokio::spawn(async move {
-        let mut log = File::create(stdout_path)
-            .await
-            .expect("failed to create output log");
-
-        let mut stdout_bufreader = BufReader::new(stdout);
-        loop {
-            let mut stdout_buf = String::new();
-            let read = stdout_bufreader.read_line(&mut stdout_buf).await;
-
-            match read {
-                // EOF
-                Ok(0) => break,
-                Ok(s) => {
-                    info!("{}", stdout_buf.trim());
-                    log.write_all(stdout_buf.as_bytes()).await.unwrap();
-                    continue;
-                }
-                Err(e) => eprintln!("{}", e),
-            }
-        }
-    });
-
-    let stderr = ccmd.stderr.take().unwrap();
-    let stderr_path = dirs.blender_stderr_path();
-    let stderr_task = tokio::spawn(async move {
-        let mut log = File::create(stderr_path)
-            .await
-            .expect("failed to create output log");
-        let mut stderr_bufreader = BufReader::new(stderr);
-
-        loop {
-            let mut stderr_buf = String::new();
-            let read = stderr_bufreader.read_line(&mut stderr_buf).await;
-
-            match read {
-                // EOF
-                Ok(0) => break,
-                Ok(s) => {
-                    error!("{}", stderr_buf.trim());
-                    log.write_all(stderr_buf.as_bytes()).await.unwrap();
-                    continue;
-                }
-                Err(e) => eprintln!("{}", e),
-            }
-        }
-    });
+    // the readers run in their own tasks, they keep the span of the job
+    let limits = dirs.log_limits();
+    let (Some(stdout), Some(stderr)) = (ccmd.stdout.take(), ccmd.stderr.take()) else {
+        return Err(io::Error::other("blender output isn't piped"));
+    };
+    let stdout_task = tokio::spawn(
+        capture(stdout, dirs.blender_stdout_path(), limits, "stdout").instrument(Span::current()),
+    );
+    let stderr_task = tokio::spawn(
+        capture(stderr, dirs.blender_stderr_path(), limits, "stderr").instrument(Span::current()),
+    );
 
     let child = Arc::new(Mutex::new(ccmd));
 
@@ -107,18 +73,108 @@ pub async fn run(
                         //println!("status: {}", status_buf.trim());
                         let msg = String::from(status_buf.trim());
                         let s = sender.lock().await;
-                        s.send(msg).unwrap();
+                        // the receiver going away must not abort the render
+                        let _ = s.send(msg);
                     },
-                    Err(e) => eprintln!("{}", e)
+                    Err(e) => error!("couldn't read blender status: {}", e)
                 }
             }
             status = c.wait() => {
-                error!("Child exited: {:?}", status);
+                match &status {
+                    Ok(status) if status.success() => debug!("blender exited"),
+                    Ok(status) => warn!("blender exited with {}", status),
+                    Err(e) => error!("couldn't wait for blender: {}", e),
+                }
 
                 //stdout_task.await.unwrap();
-                let _ = tokio::join!(stdout_task, stderr_task);
+                let (stdout, stderr) = tokio::join!(stdout_task, stderr_task);
+                for res in [stdout, stderr] {
+                    if let Ok(Err(e)) = res {
+                        error!("couldn't write blender log: {}", e);
+                    }
+                }
                 return status
             }
         }
     }
 }
+
+/// Writes the output of a child process into its log, line by line, and forwards
+/// every line to the logger at the level it is classified with. The pipe is drained
+/// even if the log can't be written, otherwise the child would block once it is full.
+async fn capture(
+    output: impl AsyncRead + Unpin,
+    path: String,
+    limits: LogLimits,
+    stream: &'static str,
+) -> io::Result<()> {
+    let mut log = match RotatingLog::create(path.clone(), limits).await {
+        Ok(log) => Some(log),
+        Err(e) => {
+            error!("couldn't create the blender log {}, dropping its lines: {}", path, e);
+            None
+        }
+    };
+    let mut lines = BufReader::new(output).lines();
+    while let Some(line) = lines.next_line().await? {
+        forward(stream, &line);
+        if let Some(writer) = &mut log
+            && let Err(e) = writer.write_line(&line).await
+        {
+            error!("couldn't write the blender log {}, dropping the rest of it: {}", path, e);
+            log = None;
+        }
+    }
+    match log {
+        Some(mut log) => log.flush().await,
+        None => Ok(()),
+    }
+}
+
+/// Logs a line of blender output, tagged with the stream it came from
+fn forward(stream: &'static str, line: &str) {
+    match classify(line) {
+        Level::ERROR => error!(target: "blender", stream, "{}", line),
+        Level::WARN => warn!(target: "blender", stream, "{}", line),
+        Level::DEBUG => debug!(target: "blender", stream, "{}", line),
+        _ => trace!(target: "blender", stream, "{}", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const OUTPUT: &[u8] = b"Fra:1 Mem:12.00M\nSaved: 'frame_00001.png'\n";
+
+    #[tokio::test]
+    async fn capture_writes_every_line() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blender.log").to_string_lossy().into_owned();
+
+        capture(OUTPUT, path.clone(), LogLimits::default(), "stdout").await.unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), OUTPUT);
+    }
+
+    #[tokio::test]
+    async fn capture_drains_without_a_log() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("missing/blender.log").to_string_lossy().into_owned();
+
+        let mut output = OUTPUT;
+        capture(&mut output, path, LogLimits::default(), "stdout").await.unwrap();
+        assert!(output.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn capture_drains_when_the_log_fails() {
+        let lines = "frame rendered\n".repeat(10_000);
+        let mut output = lines.as_bytes();
+
+        capture(&mut output, String::from("/dev/full"), LogLimits::default(), "stdout").await.unwrap();
+        assert!(output.is_empty());
+    }
+}
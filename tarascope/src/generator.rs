@@ -0,0 +1,127 @@
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::shader::{KaleidoArgs, ParseError, TextureKind};
+
+/// Largest number of kaleidoscopes a single spec may generate
+pub static MAX_COUNT: u32 = 1000;
+
+/// Recipe for a set of kaleidoscopes, e.g. ten wave kaleidoscopes with repetition 6.
+///
+/// Every kaleidoscope gets its own seed, counting up from `seed`, so the same spec
+/// always generates the same parameters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeneratorSpec {
+    /// Texture of every kaleidoscope, a random one each if not set
+    #[serde(default)]
+    pub texture: Option<TextureKind>,
+
+    /// Parameters shared by all kaleidoscopes, keyed like in the parameters json
+    /// (`repetition`, `wave_scale`, `composite_hue`, ...)
+    #[serde(default)]
+    pub fixed: Map<String, Value>,
+
+    /// Seed of the first kaleidoscope
+    #[serde(default)]
+    pub seed: u64,
+
+    /// Number of kaleidoscopes
+    pub count: u32,
+}
+
+impl GeneratorSpec {
+    /// Seeds of the kaleidoscopes, `None` if there are none or the last one doesn't fit into an u64
+    pub fn seeds(&self) -> Option<RangeInclusive<u64>> {
+        let last = self.seed.checked_add(u64::from(self.count).checked_sub(1)?)?;
+        Some(self.seed..=last)
+    }
+
+    /// Generates the parameters of every kaleidoscope, fails if a fixed
+    /// parameter is unknown or out of range
+    pub fn generate(&self) -> Result<Vec<KaleidoArgs>, ParseError> {
+        if self.count == 0 || self.count > MAX_COUNT {
+            return Err(ParseError::OutOfRangeError);
+        }
+        self.seeds()
+            .ok_or(ParseError::OutOfRangeError)?
+            .map(|seed| KaleidoArgs::seeded(seed, self.texture).with_parameters(&self.fixed))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn spec(seed: u64, count: u32) -> GeneratorSpec {
+        GeneratorSpec {
+            texture: None,
+            fixed: Map::new(),
+            seed,
+            count,
+        }
+    }
+
+    /// Parameters of the generated kaleidoscopes without their random ids
+    fn parameters(spec: &GeneratorSpec) -> Vec<Value> {
+        let mut generated = Vec::new();
+        for args in spec.generate().unwrap() {
+            let mut json = args.json();
+            json.as_object_mut().unwrap().remove("id");
+            generated.push(json);
+        }
+        generated
+    }
+
+    #[test]
+    fn counts_seeds_up_from_the_first() {
+        assert_eq!(spec(0, 3).seeds(), Some(0..=2));
+        assert_eq!(spec(40, 1).seeds(), Some(40..=40));
+        assert_eq!(spec(u64::MAX - 1, 2).seeds(), Some(u64::MAX - 1..=u64::MAX));
+        assert_eq!(spec(0, 0).seeds(), None);
+    }
+
+    #[test]
+    fn rejects_seeds_beyond_u64() {
+        assert_eq!(spec(u64::MAX, 2).seeds(), None);
+        assert_eq!(spec(u64::MAX - 1, 5).seeds(), None);
+        assert!(matches!(spec(u64::MAX, 2).generate(), Err(ParseError::OutOfRangeError)));
+        assert_eq!(spec(u64::MAX, 1).generate().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_counts_out_of_range() {
+        assert!(matches!(spec(0, 0).generate(), Err(ParseError::OutOfRangeError)));
+        assert!(matches!(spec(0, MAX_COUNT + 1).generate(), Err(ParseError::OutOfRangeError)));
+        assert_eq!(spec(0, MAX_COUNT).generate().unwrap().len(), MAX_COUNT as usize);
+    }
+
+    #[test]
+    fn same_spec_generates_the_same_parameters() {
+        let first = parameters(&spec(7, 4));
+        assert_eq!(first, parameters(&spec(7, 4)));
+        assert_ne!(first[0], first[1]);
+        // a spec starting later continues the same sequence
+        assert_eq!(first[1..], parameters(&spec(8, 3)));
+    }
+
+    #[test]
+    fn applies_the_texture_and_fixed_parameters() {
+        let mut spec = spec(0, 5);
+        spec.texture = Some(TextureKind::Wave);
+        spec.fixed.insert(String::from("repetition"), json!(6));
+
+        let generated = parameters(&spec);
+        for json in &generated {
+            assert_eq!(json["repetition"], 6);
+            assert_eq!(json["texture_index"], generated[0]["texture_index"]);
+        }
+
+        spec.fixed.insert(String::from("no_such_parameter"), json!(1));
+        assert!(matches!(spec.generate(), Err(ParseError::UnknownParameter(key)) if key == "no_such_parameter"));
+    }
+}
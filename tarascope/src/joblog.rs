@@ -0,0 +1,321 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncWriteExt},
+};
+use tracing::Level;
+
+use crate::RenderJobDirectories;
+
+/// Size caps of the logs written into a job directory
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LogLimits {
+    /// Size in bytes at which a log gets rotated
+    pub max_bytes: u64,
+
+    /// Rotated logs that are kept next to the current one, as `<log>.1`, `<log>.2`, ...
+    pub keep: usize,
+}
+
+impl Default for LogLimits {
+    fn default() -> Self {
+        Self {
+            max_bytes: 8 * 1024 * 1024,
+            keep: 3,
+        }
+    }
+}
+
+/// Names the logs of a job are listed and served under
+pub static JOB_LOGS: &[&str] = &["blender-stdout", "blender-stderr", "blender", "ffmpeg"];
+
+/// A log in a job directory
+#[derive(Debug, Serialize)]
+pub struct JobLog {
+    pub name: &'static str,
+    /// 0 for the current log, n for `<log>.n`
+    pub rotated: usize,
+    pub size: u64,
+}
+
+/// Path of the log `name` of a job, `rotated` selects an older copy
+pub fn job_log_path(dirs: &RenderJobDirectories, name: &str, rotated: usize) -> Option<String> {
+    let path = match name {
+        "blender-stdout" => dirs.blender_stdout_path(),
+        "blender-stderr" => dirs.blender_stderr_path(),
+        "blender" => dirs.blender_native_log_path(),
+        "ffmpeg" => dirs.ffmpeg_stderr_path(),
+        _ => return None,
+    };
+    Some(match rotated {
+        0 => path,
+        n => rotated_path(&path, n),
+    })
+}
+
+/// Every log of a job that exists, the rotated copies after the current one
+pub fn job_logs(dirs: &RenderJobDirectories) -> Vec<JobLog> {
+    let mut logs = Vec::new();
+    for name in JOB_LOGS {
+        for rotated in 0.. {
+            let meta = job_log_path(dirs, name, rotated).and_then(|p| std::fs::metadata(p).ok());
+            match meta {
+                Some(meta) => logs.push(JobLog {
+                    name,
+                    rotated,
+                    size: meta.len(),
+                }),
+                // the current log is missing until the first run that writes it
+                None if rotated == 0 => continue,
+                None => break,
+            }
+        }
+    }
+    logs
+}
+
+/// Path of the `n`th rotated copy of `path`
+pub fn rotated_path(path: &str, n: usize) -> String {
+    format!("{}.{}", path, n)
+}
+
+/// Moves `path` to `<path>.1`, shifting the older copies up and dropping
+/// the ones beyond `keep`. Empty or missing logs are left alone.
+pub async fn rotate(path: &str, keep: usize) -> io::Result<()> {
+    match fs::metadata(path).await {
+        Ok(meta) if meta.len() > 0 => {}
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    if keep == 0 {
+        return fs::remove_file(path).await;
+    }
+    for n in (1..keep).rev() {
+        let from = rotated_path(path, n);
+        if Path::new(&from).exists() {
+            fs::rename(&from, rotated_path(path, n + 1)).await?;
+        }
+    }
+    fs::rename(path, rotated_path(path, 1)).await
+}
+
+/// Line based log of a child process that gets rotated once it grows beyond its cap
+pub struct RotatingLog {
+    path: String,
+    limits: LogLimits,
+    file: File,
+    written: u64,
+}
+
+impl RotatingLog {
+    /// Starts a fresh log, the one of a previous attempt is rotated away
+    pub async fn create(path: String, limits: LogLimits) -> io::Result<Self> {
+        rotate(&path, limits.keep).await?;
+        let file = File::create(&path).await?;
+        Ok(Self {
+            path,
+            limits,
+            file,
+            written: 0,
+        })
+    }
+
+    /// Continues an existing log, used when several runs share one log
+    pub async fn append(path: String, limits: LogLimits) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(&path).await?;
+        let written = file.metadata().await?.len();
+        Ok(Self {
+            path,
+            limits,
+            file,
+            written,
+        })
+    }
+
+    /// Writes a single line, `line` must not contain the line break
+    pub async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.written > 0 && self.written + line.len() as u64 + 1 > self.limits.max_bytes {
+            self.file.flush().await?;
+            rotate(&self.path, self.limits.keep).await?;
+            self.file = File::create(&self.path).await?;
+            self.written = 0;
+        }
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.write_all(b"\n").await?;
+        self.written += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> io::Result<()> {
+        self.file.flush().await
+    }
+}
+
+/// Level a line of blender output is logged with. Blender writes most of its
+/// regular chatter to stderr, so the stream alone says nothing about severity.
+pub fn classify(line: &str) -> Level {
+    let line = line.trim_start();
+    if line.starts_with("Fra:") {
+        // per tile progress, hundreds of lines per frame
+        Level::TRACE
+    } else if line.starts_with("Traceback")
+        || line.starts_with("Error")
+        || line.starts_with("ERROR")
+        || line.contains("Error:")
+        || line.contains("Exception")
+        || line.contains("Segmentation fault")
+        || line.contains("Aborted")
+        || line.contains("Unable to open")
+    {
+        Level::ERROR
+    } else if line.starts_with("Warning") || line.starts_with("WARN") || line.contains("Warning:") {
+        Level::WARN
+    } else {
+        Level::DEBUG
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn log_path(dir: &TempDir) -> String {
+        dir.path().join("blender.log").to_string_lossy().into_owned()
+    }
+
+    fn read(path: &str) -> Option<String> {
+        std::fs::read_to_string(path).ok()
+    }
+
+    #[test]
+    fn classifies_blender_output() {
+        assert_eq!(classify("Fra:1 Mem:12.00M | Rendering 1 / 64 samples"), Level::TRACE);
+        assert_eq!(classify("Traceback (most recent call last):"), Level::ERROR);
+        assert_eq!(classify("  File \"loader.py\", line 3, in <module>"), Level::DEBUG);
+        assert_eq!(classify("KeyError: 'hue'"), Level::ERROR);
+        assert_eq!(classify("Error: Cannot read file"), Level::ERROR);
+        assert_eq!(classify("Segmentation fault (core dumped)"), Level::ERROR);
+        assert_eq!(classify("Warning: property not found"), Level::WARN);
+        assert_eq!(classify("   WARN (bke.modifier): skipping"), Level::WARN);
+        assert_eq!(classify("Saved: '/tmp/frame_00001.png'"), Level::DEBUG);
+        assert_eq!(classify(""), Level::DEBUG);
+    }
+
+    #[test]
+    fn limits_take_defaults_for_missing_keys() {
+        let limits: LogLimits = serde_json::from_str(r#"{"max_bytes": 1024}"#).unwrap();
+        assert_eq!(limits.max_bytes, 1024);
+        assert_eq!(limits.keep, LogLimits::default().keep);
+    }
+
+    #[tokio::test]
+    async fn rotates_and_drops_old_copies() {
+        let dir = TempDir::new().unwrap();
+        let path = log_path(&dir);
+        for run in 1..=4 {
+            std::fs::write(&path, format!("run {}\n", run)).unwrap();
+            rotate(&path, 2).await.unwrap();
+        }
+
+        assert_eq!(read(&path), None);
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("run 4\n"));
+        assert_eq!(read(&rotated_path(&path, 2)).as_deref(), Some("run 3\n"));
+        assert_eq!(read(&rotated_path(&path, 3)), None);
+    }
+
+    #[tokio::test]
+    async fn rotate_leaves_empty_and_missing_logs_alone() {
+        let dir = TempDir::new().unwrap();
+        let path = log_path(&dir);
+        rotate(&path, 2).await.unwrap();
+        assert_eq!(read(&rotated_path(&path, 1)), None);
+
+        std::fs::write(&path, "").unwrap();
+        rotate(&path, 2).await.unwrap();
+        assert_eq!(read(&path).as_deref(), Some(""));
+        assert_eq!(read(&rotated_path(&path, 1)), None);
+    }
+
+    #[tokio::test]
+    async fn rotate_without_copies_deletes() {
+        let dir = TempDir::new().unwrap();
+        let path = log_path(&dir);
+        std::fs::write(&path, "old\n").unwrap();
+        rotate(&path, 0).await.unwrap();
+
+        assert_eq!(read(&path), None);
+        assert_eq!(read(&rotated_path(&path, 1)), None);
+    }
+
+    #[tokio::test]
+    async fn rotates_once_the_cap_is_reached() {
+        let dir = TempDir::new().unwrap();
+        let path = log_path(&dir);
+        let limits = LogLimits { max_bytes: 10, keep: 1 };
+        let mut log = RotatingLog::create(path.clone(), limits).await.unwrap();
+        for line in ["aaaa", "bbbb", "cccc"] {
+            log.write_line(line).await.unwrap();
+        }
+        log.flush().await.unwrap();
+
+        assert_eq!(read(&path).as_deref(), Some("cccc\n"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("aaaa\nbbbb\n"));
+    }
+
+    #[tokio::test]
+    async fn keeps_lines_longer_than_the_cap_whole() {
+        let dir = TempDir::new().unwrap();
+        let path = log_path(&dir);
+        let limits = LogLimits { max_bytes: 4, keep: 2 };
+        let mut log = RotatingLog::create(path.clone(), limits).await.unwrap();
+        for line in ["a very long line", "another long line", "x"] {
+            log.write_line(line).await.unwrap();
+        }
+        log.flush().await.unwrap();
+
+        assert_eq!(read(&path).as_deref(), Some("x\n"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("another long line\n"));
+        assert_eq!(read(&rotated_path(&path, 2)).as_deref(), Some("a very long line\n"));
+    }
+
+    #[tokio::test]
+    async fn keeps_no_copies_without_keep() {
+        let dir = TempDir::new().unwrap();
+        let path = log_path(&dir);
+        let limits = LogLimits { max_bytes: 6, keep: 0 };
+        let mut log = RotatingLog::create(path.clone(), limits).await.unwrap();
+        for line in ["first", "second"] {
+            log.write_line(line).await.unwrap();
+        }
+        log.flush().await.unwrap();
+
+        assert_eq!(read(&path).as_deref(), Some("second\n"));
+        assert_eq!(read(&rotated_path(&path, 1)), None);
+    }
+
+    #[tokio::test]
+    async fn create_rotates_the_previous_run_and_append_continues() {
+        let dir = TempDir::new().unwrap();
+        let path = log_path(&dir);
+        std::fs::write(&path, "previous\n").unwrap();
+
+        let mut log = RotatingLog::create(path.clone(), LogLimits::default()).await.unwrap();
+        log.write_line("current").await.unwrap();
+        log.flush().await.unwrap();
+        drop(log);
+
+        let mut log = RotatingLog::append(path.clone(), LogLimits::default()).await.unwrap();
+        log.write_line("appended").await.unwrap();
+        log.flush().await.unwrap();
+
+        assert_eq!(read(&path).as_deref(), Some("current\nappended\n"));
+        assert_eq!(read(&rotated_path(&path, 1)).as_deref(), Some("previous\n"));
+    }
+}
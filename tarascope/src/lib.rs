@@ -1,21 +1,21 @@
 use std::{
-    fs::{File, create_dir},
+    fs::{File, create_dir_all, read_dir, read_to_string, remove_file},
     io::{self, BufWriter, Write},
-    path::Path,
+    path::{Path, PathBuf},
     process::ExitStatus,
-    sync::Arc,
 };
 
 use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
-use tokio::{
-    process::Command,
-    sync::{Mutex, mpsc::UnboundedSender},
-};
+use tokio::{process::Command, sync::mpsc::UnboundedSender};
 
-use crate::{exec::run, shader::KaleidoArgs};
+use crate::{error::RenderError, exec::run, joblog::LogLimits, shader::KaleidoArgs};
+pub mod assets;
 pub mod encoder;
+pub mod error;
 mod exec;
+pub mod generator;
+pub mod joblog;
 pub mod shader;
 
 static BLEND_FILE: &[u8] = include_bytes!("../kaleido.blend");
@@ -36,9 +36,29 @@ pub struct RenderStatus {
     pub frame: i32,
 }
 
+/// Progress of a single ffmpeg run, reported by the encoder
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EncodeStatus {
+    pub id: String,
+    /// Output format that is currently being encoded (mp4, gif, ...)
+    pub target: String,
+    /// Progress between 0.0 and 1.0
+    pub progress: f32,
+}
+
+/// Any message that can be sent over the status channel of a job
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum StatusUpdate {
+    Frame(RenderStatus),
+    Encode(EncodeStatus),
+}
+
 pub struct RenderJobDirectories {
     _directory: String,
     id: String,
+    /// caps of the logs written into the project folder
+    log_limits: LogLimits,
 }
 
 impl RenderJobDirectories {
@@ -46,8 +66,13 @@ impl RenderJobDirectories {
         Self {
             id,
             _directory: dir,
+            log_limits: LogLimits::default(),
         }
     }
+
+    pub fn log_limits(&self) -> LogLimits {
+        self.log_limits
+    }
     pub fn output_dir(&self) -> String {
         /*let cwd = env::current_dir().expect("cannot access current working directory");
         let p = cwd.as_path().to_str().unwrap();*/
@@ -85,16 +110,102 @@ impl RenderJobDirectories {
     pub fn blender_native_log_path(&self) -> String {
         format!("{}/blender.log", self.project_folder_path())
     }
-}
 
-fn extract_static_file(buffer: &[u8]) -> io::Result<Arc<Mutex<NamedTempFile>>> {
-    let blend_tmp = Arc::new(Mutex::new(NamedTempFile::new()?));
-    {
-        let mut b = blend_tmp.try_lock().unwrap();
-        let mut writer = BufWriter::new(b.as_file_mut());
-        writer.write(buffer)?;
+    pub fn ffmpeg_stderr_path(&self) -> String {
+        format!("{}/ffmpeg.stderr.log", self.project_folder_path())
+    }
+
+    pub fn video_path(&self) -> String {
+        format!("{}/video.mp4", self.project_folder_path())
+    }
+
+    pub fn gif_path(&self) -> String {
+        format!("{}/video.gif", self.project_folder_path())
     }
-    Ok(blend_tmp)
+
+    pub fn apng_path(&self) -> String {
+        format!("{}/video.apng", self.project_folder_path())
+    }
+
+    pub fn hls_folder_path(&self) -> String {
+        format!("{}/hls", self.project_folder_path())
+    }
+
+    pub fn hls_playlist_path(&self) -> String {
+        format!("{}/master.m3u8", self.hls_folder_path())
+    }
+
+    pub fn dash_folder_path(&self) -> String {
+        format!("{}/dash", self.project_folder_path())
+    }
+
+    pub fn dash_manifest_path(&self) -> String {
+        format!("{}/manifest.mpd", self.dash_folder_path())
+    }
+
+    /// Returns the last `lines` lines blender wrote to stderr, if there is a log
+    pub fn blender_stderr_tail(&self, lines: usize) -> Option<String> {
+        let log = read_to_string(self.blender_stderr_path()).ok()?;
+        let tail: Vec<&str> = log.lines().rev().take(lines).collect();
+        Some(tail.into_iter().rev().collect::<Vec<_>>().join("\n"))
+    }
+
+    pub fn archive_path(&self, extension: &str) -> String {
+        format!("{}/archive.{}", self.project_folder_path(), extension)
+    }
+
+    /// Deletes the packaged archives of the job, they are out of date once it renders again
+    pub fn remove_archives(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        for entry in read_dir(self.project_folder_path())? {
+            let path = entry?.path();
+            if path.file_name().unwrap_or_default().to_string_lossy().starts_with("archive.") {
+                remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Lists all rendered frames of the job, sorted by frame number
+    pub fn frame_files(&self) -> io::Result<Vec<PathBuf>> {
+        self.frame_files_with_extension("png")
+    }
+
+    /// Lists all rendered frames of the job with the given file extension, sorted by frame number
+    pub fn frame_files_with_extension(&self, extension: &str) -> io::Result<Vec<PathBuf>> {
+        let suffix = format!(".{}", extension);
+        let mut frames: Vec<PathBuf> = read_dir(self.project_folder_path())?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                let name = p.file_name().unwrap_or_default().to_string_lossy();
+                name.starts_with("frame_") && name.ends_with(&suffix)
+            })
+            .collect();
+        frames.sort();
+        Ok(frames)
+    }
+
+    /// Deletes all rendered frames (png and exr) of the job, returns how many were removed
+    pub fn remove_frames(&self) -> io::Result<usize> {
+        let mut frames = self.frame_files()?;
+        frames.extend(self.frame_files_with_extension("exr")?);
+        for frame in &frames {
+            remove_file(frame)?;
+        }
+        Ok(frames.len())
+    }
+}
+
+/// Writes an embedded file to a temporary location, which is deleted once the file is dropped
+fn extract_static_file(buffer: &[u8]) -> io::Result<NamedTempFile> {
+    let mut tmp = NamedTempFile::new()?;
+    let mut writer = BufWriter::new(tmp.as_file_mut());
+    writer.write_all(buffer)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(tmp)
 }
 
 pub struct KaleidoOutput {
@@ -129,10 +240,16 @@ impl CommandType {
             CommandType::Still(_, kaleido_args) => kaleido_args.get_id(),
         }
     }
-    fn command(&self, project: &Path, loader: &Path, dirs: &RenderJobDirectories) -> Command {
+    fn command(
+        &self,
+        blender: &str,
+        project: &Path,
+        loader: &Path,
+        dirs: &RenderJobDirectories,
+    ) -> Command {
         match self {
             CommandType::Animated(frame_start, frame_end, args) => {
-                let mut cmd = Command::new(BLENDER_PATH);
+                let mut cmd = Command::new(blender);
                 cmd
                     //.arg("kaleido.blend")
                     .arg(project.as_os_str())
@@ -158,7 +275,7 @@ impl CommandType {
                 cmd
             }
             CommandType::Still(frame, args) => {
-                let mut cmd = Command::new(BLENDER_PATH);
+                let mut cmd = Command::new(blender);
                 cmd.arg(project.as_os_str())
                     .arg("--factory-startup")
                     .arg("-o")
@@ -188,23 +305,60 @@ impl CommandType {
 pub struct Tarascope {
     /// path to the projects root (where all projects live)
     directory: String,
+
+    /// blender executable the renders are started with
+    blender: String,
+
+    /// caps of the blender and ffmpeg logs of every job
+    log_limits: LogLimits,
+
+    /// render a 16 bit EXR copy of every frame next to the png
+    exr_frames: bool,
 }
 
 impl Tarascope {
     pub fn new(directory: String) -> Self {
-        Self { directory }
+        Self::with_blender(directory, String::from(BLENDER_PATH))
+    }
+
+    pub fn with_blender(directory: String, blender: String) -> Self {
+        Self {
+            directory,
+            blender,
+            log_limits: LogLimits::default(),
+            exr_frames: false,
+        }
+    }
+
+    /// Caps the logs the jobs write into their project folders
+    pub fn with_log_limits(mut self, log_limits: LogLimits) -> Self {
+        self.log_limits = log_limits;
+        self
     }
+
+    /// Renders every frame a second time as 16 bit EXR, for the frame archives
+    pub fn with_exr_frames(mut self, exr_frames: bool) -> Self {
+        self.exr_frames = exr_frames;
+        self
+    }
+
+    /// blender executable the renders are started with
+    pub fn blender(&self) -> &str {
+        &self.blender
+    }
+
     pub fn paths_for_job(&self, job_id: &String) -> RenderJobDirectories {
         RenderJobDirectories {
             _directory: self.directory.clone(),
             id: job_id.clone(),
+            log_limits: self.log_limits,
         }
     }
     pub async fn start_render(
         &self,
         c: CommandType,
         sender: UnboundedSender<String>,
-    ) -> io::Result<KaleidoOutput> {
+    ) -> Result<KaleidoOutput, RenderError> {
         let args = c.project_args();
         let id = args.get_id();
         let dirs = self.paths_for_job(&id);
@@ -212,31 +366,40 @@ impl Tarascope {
         // Encode the parameters to base64
         let encoded = args.base64();
 
-        // create the target project
-        create_dir(dirs.project_folder_path()).expect("couldn't create project dir");
+        // create the target project, it already exists if an interrupted job gets rendered again
+        let path = dirs.project_folder_path();
+        create_dir_all(&path).map_err(|source| RenderError::ProjectDirectory { path, source })?;
         // extract Project File to a temporary location which gets dropped after the job is done
-        let project_file = extract_static_file(BLEND_FILE)?;
-        let project_borrow = project_file.try_lock().unwrap();
-        let tmp_project_path = project_borrow.path();
+        let project_file = extract_static_file(BLEND_FILE).map_err(RenderError::Extract)?;
+        let tmp_project_path = project_file.path();
 
         // same with the loader file
-        let loader_file = extract_static_file(PYTHON_LOADER)?;
-        let loader_borrow = loader_file.try_lock().unwrap();
-        let tmp_loader_path = loader_borrow.path();
+        let loader_file = extract_static_file(PYTHON_LOADER).map_err(RenderError::Extract)?;
+        let tmp_loader_path = loader_file.path();
 
         // write the parameters before the render begins
-        let json = serde_json::to_string(&args.json()).unwrap();
-        let mut file = File::create(dirs.parameters_path())?;
-        file.write_all(json.as_bytes())?;
-
-        let mut cmd = c.command(tmp_project_path, tmp_loader_path, &dirs);
+        let json = args.json().to_string();
+        File::create(dirs.parameters_path())
+            .and_then(|mut file| file.write_all(json.as_bytes()))
+            .map_err(RenderError::Parameters)?;
+
+        // blender writes its own log, it can only be rotated between attempts
+        joblog::rotate(&dirs.blender_native_log_path(), self.log_limits.keep)
+            .await
+            .map_err(RenderError::Blender)?;
+
+        let mut cmd = c.command(&self.blender, tmp_project_path, tmp_loader_path, &dirs);
+        if self.exr_frames {
+            // picked up by the loader, which adds a file output node to the compositor
+            cmd.env("TARASCOPE_EXR_FRAMES", dirs.blender_frame_path());
+        }
 
         // Append projectdata
         cmd.arg(encoded);
         // wait until the render has finished
         //let output = child.wait_with_output()?;
 
-        let status = run(&mut cmd, &dirs, sender).await?;
+        let status = run(&mut cmd, &dirs, sender).await.map_err(RenderError::Blender)?;
         Ok(KaleidoOutput::new(status, dirs.output_dir()))
     }
 }
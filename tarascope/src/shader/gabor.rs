@@ -1,11 +1,11 @@
 use std::ops::RangeInclusive;
 
 use clap_derive::Parser;
-use rand::random_range;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
-use crate::shader::{ParseError, parse_f64, validate_range};
+use crate::shader::{ParseError, normalize, parse_f64, validate_range};
 
 #[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 pub struct GaborArgs {
@@ -36,12 +36,12 @@ fn orientation_range() -> RangeInclusive<f32> {
 }
 
 impl GaborArgs {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         Self {
-            scale: random_range(scale_range()),
-            frequency: random_range(frequency_range()),
-            anisotropy: random_range(anisotropy_range()),
-            orientation: random_range(orientation_range()),
+            scale: rng.random_range(scale_range()),
+            frequency: rng.random_range(frequency_range()),
+            anisotropy: rng.random_range(anisotropy_range()),
+            orientation: rng.random_range(orientation_range()),
         }
     }
 
@@ -54,6 +54,16 @@ impl GaborArgs {
         })
     }
 
+    /// Parameters scaled into 0..=1 by their ranges
+    pub fn features(&self) -> Vec<f32> {
+        vec![
+            normalize(self.scale, scale_range()),
+            normalize(self.frequency, frequency_range()),
+            normalize(self.anisotropy, anisotropy_range()),
+            normalize(self.orientation, orientation_range()),
+        ]
+    }
+
     pub fn from_json(v: &Value) -> Result<Self, ParseError> {
         let scale = validate_range(parse_f64(v, "gabor_scale")? as f32, scale_range())?;
         let anisotropy = validate_range(parse_f64(v, "gabor_anisotropy")? as f32, anisotropy_range())?;
@@ -1,11 +1,11 @@
 use std::ops::RangeInclusive;
 
 use clap_derive::Parser;
-use rand::random_range;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
-use crate::shader::{ParseError, parse_f64, parse_u64, validate_range};
+use crate::shader::{ParseError, normalize, parse_f64, parse_u64, validate_range};
 
 #[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 pub struct MagicArgs {
@@ -32,11 +32,11 @@ fn distortion_range() -> RangeInclusive<f32> {
 }
 
 impl MagicArgs {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         Self {
-            depth: random_range(depth_range()),
-            scale: random_range(scale_range()),
-            dist: random_range(distortion_range()),
+            depth: rng.random_range(depth_range()),
+            scale: rng.random_range(scale_range()),
+            dist: rng.random_range(distortion_range()),
         }
     }
 
@@ -48,6 +48,15 @@ impl MagicArgs {
         })
     }
 
+    /// Parameters scaled into 0..=1 by their ranges
+    pub fn features(&self) -> Vec<f32> {
+        vec![
+            normalize(self.depth, depth_range()),
+            normalize(self.scale, scale_range()),
+            normalize(self.dist, distortion_range()),
+        ]
+    }
+
     pub fn from_json(v: &Value) -> Result<Self, ParseError> {
         let depth = validate_range(parse_u64(v, "magic_depth")? as u8, depth_range())?;
         let scale = validate_range(parse_f64(v, "magic_scale")? as f32, scale_range())?;
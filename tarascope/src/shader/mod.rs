@@ -1,9 +1,10 @@
 use base64::{Engine, prelude::BASE64_STANDARD};
-use clap_derive::{Parser, Subcommand};
+use clap_derive::{Parser, Subcommand, ValueEnum};
 use core::panic;
-use rand::random_range;
+use log::{debug, trace};
+use rand::{Rng, SeedableRng, rngs::StdRng};
 use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use serde_json::{Map, Value, json};
 use std::{
     fmt::Display,
     ops::RangeInclusive,
@@ -27,6 +28,7 @@ pub enum ParseError {
     WrongType(String),
     WrongTextureIndex(u8),
     OutOfRangeError,
+    UnknownParameter(String),
 }
 
 impl Display for ParseError {
@@ -37,6 +39,7 @@ impl Display for ParseError {
             }
             ParseError::WrongTextureIndex(index) => write!(f, "unknown index texture {}", index),
             ParseError::OutOfRangeError => write!(f, "value was out of range"),
+            ParseError::UnknownParameter(key) => write!(f, "unknown parameter {}", key),
         }
     }
 }
@@ -65,16 +68,49 @@ pub struct KaleidoArgs {
 
 impl KaleidoArgs {
     pub fn random() -> Self {
+        Self::random_with(&mut rand::rng(), None)
+    }
+
+    /// Random parameters that are the same for the same seed (and texture).
+    /// The id is always new.
+    pub fn seeded(seed: u64, texture: Option<TextureKind>) -> Self {
+        Self::random_with(&mut StdRng::seed_from_u64(seed), texture)
+    }
+
+    fn random_with(rng: &mut impl Rng, texture: Option<TextureKind>) -> Self {
+        let texture = match texture {
+            Some(kind) => TextureSelector::random_of(kind, rng),
+            None => TextureSelector::random(rng),
+        };
         Self {
-            texture: TextureSelector::random(),
-            polar: PolarArgs::random(),
-            composite: CompositeArgs::random(),
+            texture,
+            polar: PolarArgs::random(rng),
+            composite: CompositeArgs::random(rng),
             frames: FrameArgs::default(),
             id: Uuid::new_v4().to_string(),
             //output: OutputArgs { output_dir },
         }
     }
 
+    /// Replaces single parameters, keyed like in [`KaleidoArgs::json`] (e.g. `repetition`
+    /// or `wave_scale`). The values are checked against the same ranges as any other input.
+    pub fn with_parameters(&self, parameters: &Map<String, Value>) -> Result<Self, ParseError> {
+        let mut json = self.json();
+        for (key, value) in parameters {
+            let target = if POLAR_PARAMETERS.contains(&key.as_str()) {
+                &mut json
+            } else if json["texture"].get(key).is_some() {
+                &mut json["texture"]
+            } else if json["composite"].get(key).is_some() {
+                &mut json["composite"]
+            } else {
+                return Err(ParseError::UnknownParameter(key.clone()));
+            };
+            target[key] = value.clone();
+        }
+        Self::from_json(json)
+    }
+
     pub fn json(&self) -> Value {
         json!({
             "id": self.get_id(),
@@ -91,7 +127,7 @@ impl KaleidoArgs {
     }
 
     pub fn from_json(v: Value) -> Result<Self, ParseError> {
-        println!("{:?}", v.as_object());
+        debug!("parsing parameters {:?}", v.as_object());
         let repetition = validate_range(parse_u64(&v, "repetition")? as u8, repetition_range())?;
         let scaling = validate_range(parse_f64(&v, "scaling")? as f32, scaling_range())?;
         let rotation = validate_range(parse_f64(&v, "rotation")? as f32, rotation_range())?;
@@ -115,6 +151,59 @@ impl KaleidoArgs {
         })
     }
 
+    /// Index of the texture, kaleidoscopes with different textures are never similar
+    pub fn texture_index(&self) -> u8 {
+        self.texture.get_index()
+    }
+
+    /// All parameters that shape the kaleidoscope, each scaled into 0..=1 by its range.
+    /// The rotation is left out, it only turns the whole image.
+    pub fn features(&self) -> Vec<f32> {
+        let mut features = vec![
+            normalize(self.polar.repetition, repetition_range()),
+            normalize(self.polar.scaling, scaling_range()),
+            normalize(self.polar.pingpong, pingpong_range()),
+        ];
+        features.extend(self.composite.features());
+        features.extend(self.texture.features());
+        features
+    }
+
+    /// Which of the [features](Self::features) wrap around at the ends of their
+    /// range, like the hue. Their difference is measured the short way round.
+    pub fn circular_features(&self) -> Vec<bool> {
+        let mut circular = vec![false; 3];
+        circular.extend(CompositeArgs::circular_features());
+        circular.extend(self.texture.features().iter().map(|_| false));
+        circular
+    }
+
+    /// Root mean square difference of the [features](Self::features) of two kaleidoscopes,
+    /// the same the database computes to find near duplicates. Circular features are at
+    /// most half their range apart, doubling their difference puts opposite hues as far
+    /// apart as the ends of a plain range. `None` if the textures differ.
+    pub fn distance(&self, other: &KaleidoArgs) -> Option<f32> {
+        if self.texture_index() != other.texture_index() {
+            return None;
+        }
+        let (a, b) = (self.features(), other.features());
+        if a.is_empty() || a.len() != b.len() {
+            return None;
+        }
+
+        let sum: f32 = a
+            .iter()
+            .zip(&b)
+            .zip(self.circular_features())
+            .map(|((a, b), circular)| {
+                let diff = (a - b).abs();
+                if circular { 2.0 * diff.min(1.0 - diff) } else { diff }
+            })
+            .map(|diff| diff * diff)
+            .sum();
+        Some((sum / a.len() as f32).sqrt())
+    }
+
     pub fn base64(&self) -> String {
         BASE64_STANDARD.encode(self.json().to_string())
     }
@@ -131,6 +220,19 @@ impl KaleidoArgs {
     }
 }
 
+/// Top level keys of [`KaleidoArgs::json`] that can be replaced
+static POLAR_PARAMETERS: [&str; 4] = ["repetition", "scaling", "rotation", "pingpong"];
+
+/// Scales a value into 0..=1 by its range, so that parameters with a
+/// wide range don't outweigh the others in a distance
+fn normalize<T: Into<f32> + Copy>(value: T, range: RangeInclusive<T>) -> f32 {
+    let (start, end) = ((*range.start()).into(), (*range.end()).into());
+    if end <= start {
+        return 0.0;
+    }
+    ((value.into() - start) / (end - start)).clamp(0.0, 1.0)
+}
+
 fn repetition_range() -> RangeInclusive<u8> {
     3..=12
 }
@@ -165,17 +267,29 @@ struct PolarArgs {
 }
 
 impl PolarArgs {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         Self {
-            repetition: random_range(repetition_range()),
-            scaling: random_range(scaling_range()),
+            repetition: rng.random_range(repetition_range()),
+            scaling: rng.random_range(scaling_range()),
             //rotation: random_range(0.0..=360.0),
             rotation: 0.0,
-            pingpong: random_range(pingpong_range()),
+            pingpong: rng.random_range(pingpong_range()),
         }
     }
 }
 
+/// Texture a kaleidoscope is based on. Unoise can't be asked for,
+/// its random parameters are deprecated.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TextureKind {
+    Gabor,
+    Voronoi,
+    Wave,
+    Magic,
+    Noise,
+}
+
 #[derive(Debug, Subcommand, Clone, Serialize, Deserialize)]
 enum TextureSelector {
     /// Gabor Texture
@@ -201,24 +315,34 @@ enum TextureSelector {
 }
 
 impl TextureSelector {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         // 5 = without uNoise
         // 6 = with uNoise
         // 7 = with Textured
-        let r = random_range(0..=4);
+        let r = rng.random_range(0..=4);
         //Self::from(r)
         match r {
-            0 => TextureSelector::Gabor(GaborArgs::random()),
-            1 => TextureSelector::Voronoi(VoronoiArgs::random()),
-            2 => TextureSelector::Wave(WaveArgs::random()),
-            3 => TextureSelector::Magic(MagicArgs::random()),
-            4 => TextureSelector::Noise(NoiseArgs::random()),
-            5 => TextureSelector::Unoise(UnoiseArgs::random()),
+            0 => TextureSelector::Gabor(GaborArgs::random(rng)),
+            1 => TextureSelector::Voronoi(VoronoiArgs::random(rng)),
+            2 => TextureSelector::Wave(WaveArgs::random(rng)),
+            3 => TextureSelector::Magic(MagicArgs::random(rng)),
+            4 => TextureSelector::Noise(NoiseArgs::random(rng)),
+            5 => TextureSelector::Unoise(UnoiseArgs::random(rng)),
             6 => TextureSelector::Textured(TexturedArgs::random()),
             _ => panic!("invalid texture index"),
         }
     }
 
+    pub fn random_of(kind: TextureKind, rng: &mut impl Rng) -> Self {
+        match kind {
+            TextureKind::Gabor => TextureSelector::Gabor(GaborArgs::random(rng)),
+            TextureKind::Voronoi => TextureSelector::Voronoi(VoronoiArgs::random(rng)),
+            TextureKind::Wave => TextureSelector::Wave(WaveArgs::random(rng)),
+            TextureKind::Magic => TextureSelector::Magic(MagicArgs::random(rng)),
+            TextureKind::Noise => TextureSelector::Noise(NoiseArgs::random(rng)),
+        }
+    }
+
     fn get_index(&self) -> u8 {
         match self {
             TextureSelector::Gabor(_) => 0,
@@ -231,6 +355,19 @@ impl TextureSelector {
         }
     }
 
+    fn features(&self) -> Vec<f32> {
+        match self {
+            TextureSelector::Gabor(args) => args.features(),
+            TextureSelector::Voronoi(args) => args.features(),
+            TextureSelector::Wave(args) => args.features(),
+            TextureSelector::Magic(args) => args.features(),
+            TextureSelector::Noise(args) => args.features(),
+            TextureSelector::Unoise(args) => args.features(),
+            // the image decides the look, its path doesn't say anything about it
+            TextureSelector::Textured(_) => Vec::new(),
+        }
+    }
+
     fn json(&self) -> Value {
         match self {
             TextureSelector::Gabor(gabor_args) => gabor_args.json(),
@@ -313,14 +450,28 @@ fn saturation_range() -> RangeInclusive<f32> {
 }
 
 impl CompositeArgs {
-    fn random() -> Self {
+    fn random(rng: &mut impl Rng) -> Self {
         Self {
-            lens_distortion: random_range(lens_distortion_range()),
-            lens_dispersion: random_range(lens_dispersion_range()),
-            hue: random_range(hue_range()),
-            saturation: random_range(saturation_range()),
+            lens_distortion: rng.random_range(lens_distortion_range()),
+            lens_dispersion: rng.random_range(lens_dispersion_range()),
+            hue: rng.random_range(hue_range()),
+            saturation: rng.random_range(saturation_range()),
         }
     }
+    fn features(&self) -> Vec<f32> {
+        vec![
+            normalize(self.lens_distortion, lens_distortion_range()),
+            normalize(self.lens_dispersion, lens_dispersion_range()),
+            normalize(self.hue, hue_range()),
+            normalize(self.saturation, saturation_range()),
+        ]
+    }
+
+    /// A hue of 0 and 1 is the same color
+    fn circular_features() -> [bool; 4] {
+        [false, false, true, false]
+    }
+
     fn json(&self) -> Value {
         json!({
             "composite_lens_distortion": self.lens_distortion,
@@ -362,7 +513,7 @@ where
     if range.contains(&value) {
         Ok(value)
     } else {
-        eprintln!("{:?} {:?}", value, range);
+        debug!("{:?} is out of range {:?}", value, range);
         Err(ParseError::OutOfRangeError)
     }
 }
@@ -377,7 +528,7 @@ fn parse(v: &Value, key: &'static str) -> Result<Value, ParseError> {
 }
 fn parse_u64(v: &Value, key: &'static str) -> Result<u64, ParseError> {
     let value = v[key].as_u64();
-    println!("[DEBUG/u64] {}: {:?}", key, value);
+    trace!("{}: {:?}", key, value);
     if let Some(value) = value {
         Ok(value)
     } else {
@@ -386,7 +537,7 @@ fn parse_u64(v: &Value, key: &'static str) -> Result<u64, ParseError> {
 }
 fn parse_f64(v: &Value, key: &'static str) -> Result<f64, ParseError> {
     let value = v[key].as_f64();
-    println!("[DEBUG/f64] {}: {:?}", key, value);
+    trace!("{}: {:?}", key, value);
     if let Some(value) = value {
         Ok(value)
     } else {
@@ -395,7 +546,7 @@ fn parse_f64(v: &Value, key: &'static str) -> Result<f64, ParseError> {
 }
 fn parse_string(v: &Value, key: &'static str) -> Result<String, ParseError> {
     let value = v[key].as_str();
-    println!("[DEBUG/string] {}: {:?}", key, value);
+    trace!("{}: {:?}", key, value);
     if let Some(value) = value {
         Ok(String::from(value))
     } else {
@@ -473,3 +624,68 @@ pub struct OutputArgs {
     #[arg(short, long)]
     pub output_dir: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wave(parameters: Value) -> KaleidoArgs {
+        KaleidoArgs::seeded(1, Some(TextureKind::Wave))
+            .with_parameters(parameters.as_object().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn normalizes_into_the_range() {
+        assert_eq!(normalize(3u8, repetition_range()), 0.0);
+        assert_eq!(normalize(12u8, repetition_range()), 1.0);
+        assert_eq!(normalize(2.5, 0.5..=4.5), 0.5);
+        assert_eq!(normalize(-0.75, lens_distortion_range()), 0.5);
+        // values outside the range are clamped, empty ranges don't divide by zero
+        assert_eq!(normalize(20.0, scaling_range()), 1.0);
+        assert_eq!(normalize(0.0, scaling_range()), 0.0);
+        assert_eq!(normalize(1.0, 1.0..=1.0), 0.0);
+    }
+
+    #[test]
+    fn features_are_normalized() {
+        for seed in 0..20 {
+            let kargs = KaleidoArgs::seeded(seed, None);
+            let features = kargs.features();
+            assert_eq!(features.len(), kargs.circular_features().len());
+            assert!(features.iter().all(|f| (0.0..=1.0).contains(f)), "{:?}", features);
+        }
+    }
+
+    #[test]
+    fn rotation_is_no_feature() {
+        let a = wave(json!({ "rotation": 0.0 }));
+        let b = wave(json!({ "rotation": 90.0 }));
+        assert_eq!(a.features(), b.features());
+        assert_eq!(a.distance(&b), Some(0.0));
+    }
+
+    #[test]
+    fn hue_distance_wraps_around() {
+        let red = wave(json!({ "composite_hue": 0.05 }));
+        let also_red = wave(json!({ "composite_hue": 0.95 }));
+        let cyan = wave(json!({ "composite_hue": 0.55 }));
+
+        let near = red.distance(&also_red).unwrap();
+        let far = red.distance(&cyan).unwrap();
+        assert!(near < far, "{} >= {}", near, far);
+
+        // only the hue differs, doubled and averaged over all features
+        let features = red.features().len() as f32;
+        assert!((near - 0.2 / features.sqrt()).abs() < 1e-5);
+        assert!((far - 1.0 / features.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn different_textures_are_never_close() {
+        let wave = KaleidoArgs::seeded(1, Some(TextureKind::Wave));
+        let magic = KaleidoArgs::seeded(1, Some(TextureKind::Magic));
+        assert_eq!(wave.distance(&wave), Some(0.0));
+        assert_eq!(wave.distance(&magic), None);
+    }
+}
@@ -1,11 +1,11 @@
 use std::ops::RangeInclusive;
 
 use clap_derive::Parser;
-use rand::random_range;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
-use crate::shader::{ParseError, parse_f64, validate_range};
+use crate::shader::{ParseError, normalize, parse_f64, validate_range};
 
 #[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 pub struct NoiseArgs {
@@ -46,13 +46,13 @@ fn distortion_range() -> RangeInclusive<f32> {
 }
 
 impl NoiseArgs {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         Self {
-            scale: random_range(scale_range()),
-            detail: random_range(detail_range()),
-            roughness: random_range(roughness_range()),
-            lacunarity: random_range(lacunarity_range()),
-            distortion: random_range(distortion_range()),
+            scale: rng.random_range(scale_range()),
+            detail: rng.random_range(detail_range()),
+            roughness: rng.random_range(roughness_range()),
+            lacunarity: rng.random_range(lacunarity_range()),
+            distortion: rng.random_range(distortion_range()),
         }
     }
 
@@ -66,6 +66,17 @@ impl NoiseArgs {
         })
     }
 
+    /// Parameters scaled into 0..=1 by their ranges
+    pub fn features(&self) -> Vec<f32> {
+        vec![
+            normalize(self.scale, scale_range()),
+            normalize(self.detail, detail_range()),
+            normalize(self.roughness, roughness_range()),
+            normalize(self.lacunarity, lacunarity_range()),
+            normalize(self.distortion, distortion_range()),
+        ]
+    }
+
     pub fn from_json(v: &Value) -> Result<Self, ParseError> {
         let scale = validate_range(parse_f64(v, "noise_scale")? as f32, scale_range())?;
         let detail = validate_range(parse_f64(v, "noise_detail")? as f32, detail_range())?;
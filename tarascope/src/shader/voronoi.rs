@@ -1,11 +1,11 @@
 use std::ops::RangeInclusive;
 
 use clap_derive::Parser;
-use rand::random_range;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
-use crate::shader::{ParseError, parse_f64, validate_range};
+use crate::shader::{ParseError, normalize, parse_f64, validate_range};
 
 fn scale_range() -> RangeInclusive<f32> {
     2.0..=20.0
@@ -30,11 +30,11 @@ pub struct VoronoiArgs {
 }
 
 impl VoronoiArgs {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         Self {
-            scale: random_range(scale_range()),
-            detail: random_range(detail_range()),
-            randomize: random_range(randomize_range()),
+            scale: rng.random_range(scale_range()),
+            detail: rng.random_range(detail_range()),
+            randomize: rng.random_range(randomize_range()),
         }
     }
     pub fn json(&self) -> Value {
@@ -45,6 +45,15 @@ impl VoronoiArgs {
         })
     }
 
+    /// Parameters scaled into 0..=1 by their ranges
+    pub fn features(&self) -> Vec<f32> {
+        vec![
+            normalize(self.scale, scale_range()),
+            normalize(self.detail, detail_range()),
+            normalize(self.randomize, randomize_range()),
+        ]
+    }
+
     pub fn from_json(v: &Value) -> Result<Self, ParseError> {
         let scale = validate_range(parse_f64(v, "voronoi_scale")? as f32, scale_range())?;
         let detail = validate_range(parse_f64(v, "voronoi_detail")? as f32, detail_range())?;
@@ -1,11 +1,11 @@
 use std::ops::RangeInclusive;
 
 use clap_derive::Parser;
-use rand::random_range;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
-use crate::shader::{ParseError, parse_f64, validate_range};
+use crate::shader::{ParseError, normalize, parse_f64, validate_range};
 
 #[derive(Parser, Debug, Clone, Serialize, Deserialize)]
 pub struct WaveArgs {
@@ -42,13 +42,13 @@ fn phase_offset_range() -> RangeInclusive<f32> {
 }
 
 impl WaveArgs {
-    pub fn random() -> Self {
+    pub fn random(rng: &mut impl Rng) -> Self {
         Self {
-            scale: random_range(scale_range()),
-            distortion: random_range(distortion_range()),
-            detail: random_range(detail_range()),
-            detail_roughness: random_range(detail_roughness_range()),
-            phase_offset: random_range(phase_offset_range()),
+            scale: rng.random_range(scale_range()),
+            distortion: rng.random_range(distortion_range()),
+            detail: rng.random_range(detail_range()),
+            detail_roughness: rng.random_range(detail_roughness_range()),
+            phase_offset: rng.random_range(phase_offset_range()),
         }
     }
     pub fn json(&self) -> Value {
@@ -61,6 +61,17 @@ impl WaveArgs {
         })
     }
 
+    /// Parameters scaled into 0..=1 by their ranges
+    pub fn features(&self) -> Vec<f32> {
+        vec![
+            normalize(self.scale, scale_range()),
+            normalize(self.distortion, distortion_range()),
+            normalize(self.detail, detail_range()),
+            normalize(self.detail_roughness, detail_roughness_range()),
+            normalize(self.phase_offset, phase_offset_range()),
+        ]
+    }
+
     pub fn from_json(v: &Value) -> Result<Self, ParseError> {
         let detail = validate_range(parse_f64(v, "wave_detail")? as f32, detail_range())?;
         let scale = validate_range(parse_f64(v, "wave_scale")? as f32, scale_range())?;